@@ -0,0 +1,8 @@
+fn main() {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_build::configure()
+        .build_server(false)
+        .build_transport(false)
+        .compile_protos(&["proto/vote.proto"], &["proto"])
+        .unwrap();
+}
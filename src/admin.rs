@@ -0,0 +1,471 @@
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use warp::{Filter, Rejection, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use log::warn;
+use crate::cache_task::CacheTask;
+use crate::auth_failure::{self, AuthFailure, ClientInfo};
+use crate::config;
+use crate::constants::AUTH_SOURCE_ADMIN;
+use crate::vote_cache::QueueFilter;
+use crate::vote_handler::QueueSelector;
+
+const DEFAULT_PAGE_LIMIT: usize = 50;
+const MAX_PAGE_LIMIT: usize = 500;
+
+#[derive(Debug, Deserialize)]
+struct SinkQuery {
+    route: Option<String>,
+    sink: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    route: Option<String>,
+    sink: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct QueueQuery {
+    route: Option<String>,
+    sink: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    source: Option<String>,
+    bot: Option<u64>,
+    guild: Option<u64>,
+    user: Option<u64>,
+}
+
+/**
+The Authorization header of an admin request with the client it came from, to report failures
+*/
+struct AdminAuth {
+    authorization: Option<String>,
+    client: ClientInfo,
+}
+
+fn admin_auth() -> impl Filter<Extract=(AdminAuth,), Error=Rejection> + Clone {
+    return warp::header::optional::<String>("authorization")
+        .and(auth_failure::client_info())
+        .map(|authorization: Option<String>, client: ClientInfo| AdminAuth { authorization, client });
+}
+
+impl SinkQuery {
+    fn selector(self) -> QueueSelector {
+        return QueueSelector { route: self.route, sink: self.sink };
+    }
+}
+
+/**
+Routes below /admin, all of them require VOTE_ADMIN_TOKEN in the Authorization header. The queue of a sink
+is selected by the route and sink query parameters, defaulting to the endpoint of the default route
+*/
+pub fn routes(tx: Sender<CacheTask>) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let rest_tx = tx.clone();
+    let list_dead_letters = warp::get()
+        .and(warp::path!("admin" / "dead-letters"))
+        .and(admin_auth())
+        .and(warp::query::<PageQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|authorization: AdminAuth, page: PageQuery, tx: Sender<CacheTask>| async move {
+            let offset = page.offset.unwrap_or(0);
+            let limit = page.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_list_dead_letters_task(QueueSelector { route: page.route, sink: page.sink }, offset, limit, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let requeue_dead_letter = warp::post()
+        .and(warp::path!("admin" / "dead-letters" / u64 / "requeue"))
+        .and(admin_auth())
+        .and(warp::query::<SinkQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|id: u64, authorization: AdminAuth, query: SinkQuery, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_requeue_dead_letter_task(query.selector(), id, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let purge_dead_letter = warp::delete()
+        .and(warp::path!("admin" / "dead-letters" / u64))
+        .and(admin_auth())
+        .and(warp::query::<SinkQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|id: u64, authorization: AdminAuth, query: SinkQuery, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_purge_dead_letters_task(query.selector(), Some(id), reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let purge_dead_letters = warp::delete()
+        .and(warp::path!("admin" / "dead-letters"))
+        .and(admin_auth())
+        .and(warp::query::<SinkQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|authorization: AdminAuth, query: SinkQuery, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_purge_dead_letters_task(query.selector(), None, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let list_queue = warp::get()
+        .and(warp::path!("admin" / "queue"))
+        .and(admin_auth())
+        .and(warp::query::<QueueQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|authorization: AdminAuth, query: QueueQuery, tx: Sender<CacheTask>| async move {
+            let offset = query.offset.unwrap_or(0);
+            let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+            let filter = QueueFilter {
+                source: query.source,
+                bot: query.bot,
+                guild: query.guild,
+                user: query.user,
+            };
+            let selector = QueueSelector { route: query.route, sink: query.sink };
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_list_queue_task(selector, filter, offset, limit, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let get_queued = warp::get()
+        .and(warp::path!("admin" / "queue" / u64))
+        .and(admin_auth())
+        .and(warp::query::<SinkQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|id: u64, authorization: AdminAuth, query: SinkQuery, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_get_queued_task(query.selector(), id, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let delete_queued = warp::delete()
+        .and(warp::path!("admin" / "queue" / u64))
+        .and(admin_auth())
+        .and(warp::query::<SinkQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|id: u64, authorization: AdminAuth, query: SinkQuery, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_delete_queued_task(query.selector(), id, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let force_resend = warp::post()
+        .and(warp::path!("admin" / "queue" / "resend"))
+        .and(admin_auth())
+        .and(warp::query::<SinkQuery>())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|authorization: AdminAuth, query: SinkQuery, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_force_resend_task(query.selector(), reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let pause_resend = warp::post()
+        .and(warp::path!("admin" / "queue" / "pause"))
+        .and(admin_auth())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|authorization: AdminAuth, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_pause_resend_task(true, reply)
+            }).await;
+        });
+    let rest_tx = tx.clone();
+    let resume_resend = warp::post()
+        .and(warp::path!("admin" / "queue" / "resume"))
+        .and(admin_auth())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and_then(|authorization: AdminAuth, tx: Sender<CacheTask>| async move {
+            return process_admin_request(tx, authorization, |reply| {
+                CacheTask::create_pause_resend_task(false, reply)
+            }).await;
+        });
+
+    return list_dead_letters
+        .or(requeue_dead_letter).unify()
+        .or(purge_dead_letter).unify()
+        .or(purge_dead_letters).unify()
+        .or(list_queue).unify()
+        .or(get_queued).unify()
+        .or(delete_queued).unify()
+        .or(force_resend).unify()
+        .or(pause_resend).unify()
+        .or(resume_resend).unify()
+        .boxed();
+}
+
+/**
+Hands the task to the processing loop and responds with its reply, a null reply means not found
+*/
+async fn process_admin_request<F>(sender: Sender<CacheTask>, auth: AdminAuth, create_task: F)
+                                  -> Result<Box<dyn Reply>, Rejection>
+    where F: FnOnce(oneshot::Sender<Value>) -> CacheTask {
+    if let Err(failure) = authorize(&auth) {
+        auth_failure::report(AUTH_SOURCE_ADMIN, &failure, &auth.client);
+        return Ok(Box::new(StatusCode::UNAUTHORIZED));
+    }
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if sender.send(create_task(reply_tx)).await.is_err() {
+        return Ok(Box::new(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    return match tokio::time::timeout(config::get().health_timeout(), reply_rx).await {
+        Ok(Ok(Value::Null)) => Ok(Box::new(StatusCode::NOT_FOUND)),
+        Ok(Ok(value)) => Ok(Box::new(warp::reply::json(&value))),
+        Ok(Err(_)) => Ok(Box::new(StatusCode::SERVICE_UNAVAILABLE)),
+        Err(_) => {
+            warn!("Admin request was not answered within {:?}", config::get().health_timeout());
+            Ok(Box::new(StatusCode::SERVICE_UNAVAILABLE))
+        }
+    };
+}
+
+/**
+Admin requests are only authorized with VOTE_ADMIN_TOKEN, all of them fail if it is unset
+*/
+fn authorize(auth: &AdminAuth) -> Result<(), AuthFailure> {
+    return match (auth.authorization.as_deref(), config::get().admin_token()) {
+        (None, _) => Err(AuthFailure::MissingHeader),
+        (Some(provided), Some(expected)) if auth_failure::secrets_equal(provided, expected) => Ok(()),
+        _ => Err(AuthFailure::WrongToken),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use std::time::Duration;
+    use crate::constants::{DEFAULT_ROUTE, PRIMARY_SINK};
+    use crate::forward_result::ForwardResult;
+    use crate::metrics;
+    use crate::vote_cache::MemoryVoteCache;
+    use crate::vote_handler::{SinkQueue, VoteHandler};
+    use crate::vote_request::VoteRequest;
+    use crate::vote_sink::VoteSink;
+
+    const ADMIN_TOKEN: &str = "admin-secret";
+
+    /**
+    Fails every delivery, so every vote stays in the queue
+    */
+    struct UnavailableSink;
+
+    #[async_trait]
+    impl VoteSink for UnavailableSink {
+        async fn deliver(&self, _vote: &VoteRequest) -> ForwardResult {
+            return ForwardResult::Retryable { reason: "unavailable".to_owned(), retry_after: None };
+        }
+    }
+
+    /**
+    Refuses every vote, so every vote is moved to the dead letters
+    */
+    struct RejectingSink;
+
+    #[async_trait]
+    impl VoteSink for RejectingSink {
+        async fn deliver(&self, _vote: &VoteRequest) -> ForwardResult {
+            return ForwardResult::Rejected { reason: "bad request".to_owned() };
+        }
+    }
+
+    /**
+    The admin routes on a processing loop whose only sink fails, with the given users' votes queued
+    */
+    async fn admin_with_queued(users: &[u64]) -> BoxedFilter<(Box<dyn Reply>,)> {
+        return admin_with(Box::new(UnavailableSink), users, "/admin/queue").await;
+    }
+
+    /**
+    The admin routes on a processing loop whose only sink rejects votes, with the given users' votes dead lettered
+    */
+    async fn admin_with_dead_letters(users: &[u64]) -> BoxedFilter<(Box<dyn Reply>,)> {
+        return admin_with(Box::new(RejectingSink), users, "/admin/dead-letters").await;
+    }
+
+    /**
+    The admin routes on a processing loop with the given sink, once the given users' votes are listed by `listed`
+    */
+    async fn admin_with(sink: Box<dyn VoteSink>, users: &[u64], listed: &str) -> BoxedFilter<(Box<dyn Reply>,)> {
+        config::install_test_config();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<CacheTask>(128);
+        let queue = SinkQueue::new(DEFAULT_ROUTE.to_owned(), PRIMARY_SINK.to_owned(), sink,
+                                   Box::new(MemoryVoteCache::new()));
+        let handler = VoteHandler::new(vec![queue]);
+        tokio::spawn(async move {
+            while let Some(task) = rx.recv().await {
+                handler.process(task);
+            }
+        });
+        for user in users {
+            let vote = format!(r#"{{"bot":"1","user":"{}","type":"upvote","isWeekend":false,"query":null}}"#, user);
+            tx.send(CacheTask::create_vote_task(serde_json::from_str(vote.as_str()).unwrap())).await.unwrap();
+        }
+        let routes = routes(tx);
+        for _ in 0..500 {
+            if request(&routes, "GET", listed).await.1["total"] == users.len() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        return routes;
+    }
+
+    async fn request(routes: &BoxedFilter<(Box<dyn Reply>,)>, method: &str, path: &str) -> (StatusCode, Value) {
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .header("authorization", ADMIN_TOKEN)
+            .reply(routes)
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        return (response.status(), body);
+    }
+
+    #[tokio::test]
+    async fn lists_and_shows_queued_votes() {
+        let routes = admin_with_queued(&[1, 2]).await;
+
+        let (status, queue) = request(&routes, "GET", "/admin/queue").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(queue["total"], 2);
+        assert_eq!(queue["paused"], false);
+        let (_, queue) = request(&routes, "GET", "/admin/queue?user=2&limit=10").await;
+        assert_eq!(queue["total"], 1);
+        let id = queue["votes"][0]["id"].as_u64().unwrap();
+
+        let (status, vote) = request(&routes, "GET", format!("/admin/queue/{}", id).as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(vote["vote"]["user"], "2");
+        assert_eq!(vote["history"][0]["error"], "unavailable");
+        assert_eq!(request(&routes, "GET", "/admin/queue/999").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(request(&routes, "GET", "/admin/queue?route=unknown").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn deletes_queued_vote() {
+        let routes = admin_with_queued(&[1, 2]).await;
+        let (_, queue) = request(&routes, "GET", "/admin/queue?user=1").await;
+        let id = queue["votes"][0]["id"].as_u64().unwrap();
+
+        let (status, deleted) = request(&routes, "DELETE", format!("/admin/queue/{}", id).as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(deleted["deleted"], id);
+        assert_eq!(request(&routes, "DELETE", format!("/admin/queue/{}", id).as_str()).await.0, StatusCode::NOT_FOUND);
+        let (_, queue) = request(&routes, "GET", "/admin/queue").await;
+        assert_eq!(queue["total"], 1);
+        assert_eq!(queue["votes"][0]["vote"]["user"], "2");
+    }
+
+    #[tokio::test]
+    async fn resends_queued_votes_right_away() {
+        let routes = admin_with_queued(&[1, 2]).await;
+
+        let (status, resent) = request(&routes, "POST", "/admin/queue/resend").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resent["expedited"], 2);
+        for _ in 0..500 {
+            let (_, queue) = request(&routes, "GET", "/admin/queue").await;
+            // the first failure ends the run, as the sink is likely unavailable
+            if queue["votes"].as_array().unwrap().iter().any(|vote| vote["attempts"] == 2) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Votes were not resent");
+    }
+
+    #[tokio::test]
+    async fn requeues_dead_letter() {
+        let routes = admin_with_dead_letters(&[1, 2]).await;
+        let (status, dead) = request(&routes, "GET", "/admin/dead-letters").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(dead["total"], 2);
+        assert_eq!(dead["deadLetters"][0]["reason"], "bad request");
+        let id = dead["deadLetters"][0]["id"].as_u64().unwrap();
+
+        let path = format!("/admin/dead-letters/{}/requeue", id);
+        let (status, requeued) = request(&routes, "POST", path.as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(requeued["requeued"], id);
+        assert_eq!(request(&routes, "POST", path.as_str()).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(request(&routes, "GET", "/admin/dead-letters").await.1["total"], 1);
+        let (_, queue) = request(&routes, "GET", "/admin/queue").await;
+        assert_eq!(queue["total"], 1);
+        assert_eq!(queue["votes"][0]["attempts"], 0);
+    }
+
+    #[tokio::test]
+    async fn purges_dead_letters() {
+        let routes = admin_with_dead_letters(&[1, 2, 3]).await;
+        let (_, dead) = request(&routes, "GET", "/admin/dead-letters?limit=1").await;
+        let id = dead["deadLetters"][0]["id"].as_u64().unwrap();
+
+        let path = format!("/admin/dead-letters/{}", id);
+        let (status, purged) = request(&routes, "DELETE", path.as_str()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(purged["purged"], 1);
+        assert_eq!(request(&routes, "DELETE", path.as_str()).await.0, StatusCode::NOT_FOUND);
+
+        let (status, purged) = request(&routes, "DELETE", "/admin/dead-letters").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(purged["purged"], 2);
+        assert_eq!(request(&routes, "GET", "/admin/dead-letters").await.1["total"], 0);
+        assert_eq!(request(&routes, "DELETE", "/admin/dead-letters").await.1["purged"], 0);
+    }
+
+    #[tokio::test]
+    async fn pauses_and_resumes_resending() {
+        let routes = admin_with_queued(&[]).await;
+
+        let (status, paused) = request(&routes, "POST", "/admin/queue/pause").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(paused["paused"], true);
+        assert_eq!(request(&routes, "GET", "/admin/queue").await.1["paused"], true);
+
+        let (_, paused) = request(&routes, "POST", "/admin/queue/resume").await;
+        assert_eq!(paused["paused"], false);
+        assert_eq!(request(&routes, "GET", "/admin/queue").await.1["paused"], false);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_request_is_reported() {
+        let routes = admin_with_queued(&[]).await;
+        let wrong_token = || metrics::UNAUTHORIZED_REQUESTS.with_label_values(&[AUTH_SOURCE_ADMIN, "wrong_token"]).get();
+        let missing_header = || {
+            metrics::UNAUTHORIZED_REQUESTS.with_label_values(&[AUTH_SOURCE_ADMIN, "missing_header"]).get()
+        };
+        let (wrong_before, missing_before) = (wrong_token(), missing_header());
+
+        let response = warp::test::request().method("GET").path("/admin/queue")
+            .header("authorization", "secret")
+            .reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = warp::test::request().method("POST").path("/admin/queue/pause").reply(&routes).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        assert_eq!(wrong_token(), wrong_before + 1);
+        assert_eq!(missing_header(), missing_before + 1);
+    }
+
+    #[tokio::test]
+    async fn unanswered_request_is_unavailable() {
+        config::install_test_config();
+        // the processing loop takes the task but never answers it
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<CacheTask>(128);
+        let stalled = tokio::spawn(async move {
+            let _tasks: Vec<CacheTask> = vec![rx.recv().await.unwrap()];
+            tokio::time::sleep(Duration::from_secs(3600)).await;
+        });
+
+        let (status, _) = request(&routes(tx), "GET", "/admin/queue").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        stalled.abort();
+    }
+}
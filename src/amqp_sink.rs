@@ -0,0 +1,221 @@
+use async_trait::async_trait;
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties};
+use lapin::options::{BasicPublishOptions, ConfirmSelectOptions};
+use lapin::publisher_confirm::Confirmation;
+use log::{info, warn};
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use crate::config;
+use crate::forward_result::ForwardResult;
+use crate::vote_request::VoteRequest;
+use crate::vote_sink::VoteSink;
+
+const DELIVERY_MODE_PERSISTENT: u8 = 2;
+const REPLY_CODE_ACCESS_REFUSED: u16 = 403;
+
+/**
+A channel in confirm mode on a connection to the broker of the sink
+*/
+struct AmqpChannel {
+    uri: String,
+    connection: Connection,
+    channel: Channel,
+}
+
+/**
+How the broker answered a publish
+*/
+#[derive(Debug)]
+enum Published {
+    Confirmed,
+    Returned { reply_text: String },
+    Nacked,
+    Unconfirmed,
+}
+
+impl From<Confirmation> for Published {
+    fn from(confirmation: Confirmation) -> Published {
+        return match confirmation {
+            Confirmation::Ack(None) => Published::Confirmed,
+            Confirmation::Ack(Some(returned)) => Published::Returned { reply_text: returned.reply_text.to_string() },
+            Confirmation::Nack(_) => Published::Nacked,
+            Confirmation::NotRequested => Published::Unconfirmed,
+        };
+    }
+}
+
+/**
+Publishes votes as persistent JSON messages to an exchange of an AMQP broker. Every publish waits
+for the broker's confirm, a nack or an unroutable message is retried like a failed request.
+*/
+pub struct AmqpSink {
+    route: String,
+    name: String,
+    channel: Mutex<Option<AmqpChannel>>,
+}
+
+impl AmqpSink {
+    pub fn new(route: &str, name: &str) -> AmqpSink {
+        return AmqpSink {
+            route: route.to_owned(),
+            name: name.to_owned(),
+            channel: Mutex::new(None),
+        };
+    }
+
+    /**
+    Publishes the vote on the open channel, connecting first if there is none or the broker changed
+    */
+    async fn publish(&self, uri: &str, exchange: &str, routing_key: &str, vote: &VoteRequest)
+                     -> Result<Published, lapin::Error> {
+        let mut current = self.channel.lock().await;
+        let reusable = current.as_ref()
+            .is_some_and(|open| open.uri == uri && open.channel.status().connected());
+        if !reusable {
+            if let Some(stale) = current.take() {
+                let _ = stale.connection.close(0, "reconnecting").await;
+            }
+            let connection = Connection::connect(uri, ConnectionProperties::default()).await?;
+            let channel = connection.create_channel().await?;
+            channel.confirm_select(ConfirmSelectOptions::default()).await?;
+            info!("Connected amqp sink {} of route {}", self.name, self.route);
+            *current = Some(AmqpChannel { uri: uri.to_owned(), connection, channel });
+        }
+        let channel = &current.as_ref().unwrap().channel;
+        let mut properties = BasicProperties::default()
+            .with_content_type("application/json".into())
+            .with_delivery_mode(DELIVERY_MODE_PERSISTENT);
+        if let Some(key) = vote.idempotency_key.as_ref() {
+            properties = properties.with_message_id(key.as_str().into());
+        }
+        let payload = serde_json::to_vec(vote).unwrap();
+        let options = BasicPublishOptions { mandatory: true, ..BasicPublishOptions::default() };
+        let confirm = channel.basic_publish(exchange, routing_key, options, payload.as_slice(), properties).await;
+        let result = match confirm {
+            Ok(confirm) => confirm.await,
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            *current = None;
+        }
+        return result.map(Published::from);
+    }
+
+    /**
+    Maps the answer of the broker to the outcome of the delivery
+    */
+    fn classify(&self, published: Result<Published, lapin::Error>, elapsed_ms: u128) -> ForwardResult {
+        return match published {
+            Ok(Published::Confirmed) => ForwardResult::Delivered,
+            Ok(Published::Returned { reply_text }) => {
+                warn!("Broker of amqp sink {} of route {} could not route the vote", self.name, self.route);
+                ForwardResult::Retryable {
+                    reason: format!("Message was returned unroutable: {}", reply_text),
+                    retry_after: None,
+                }
+            }
+            Ok(Published::Nacked) => {
+                warn!("Broker of amqp sink {} of route {} nacked the vote", self.name, self.route);
+                ForwardResult::Retryable { reason: "Broker nacked the message".to_owned(), retry_after: None }
+            }
+            Ok(Published::Unconfirmed) => ForwardResult::Retryable {
+                reason: "Broker did not confirm the message".to_owned(),
+                retry_after: None,
+            },
+            Err(lapin::Error::ProtocolError(err)) if err.get_id() == REPLY_CODE_ACCESS_REFUSED => {
+                ForwardResult::Unauthorized { reason: format!("Broker refused access: {}", err) }
+            }
+            Err(err) => {
+                warn!("Publishing to amqp sink {} of route {} failed after {}ms!", self.name, self.route, elapsed_ms);
+                ForwardResult::Retryable {
+                    reason: format!("Publish failed after {}ms: {}", elapsed_ms, err),
+                    retry_after: None,
+                }
+            }
+        };
+    }
+}
+
+#[async_trait]
+impl VoteSink for AmqpSink {
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+        let config = config::get();
+        let target = config.sink(self.route.as_str(), self.name.as_str())
+            .and_then(|sink| Some((sink.endpoint.as_deref()?, sink.exchange.as_deref().unwrap_or(""),
+                                   sink.routing_key.as_deref()?)));
+        let (uri, exchange, routing_key) = match target {
+            Some(target) => target,
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} is not configured", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let start = SystemTime::now();
+        let result = tokio::time::timeout(config.sink_timeout(), self.publish(uri, exchange, routing_key, vote)).await;
+        let elapsed_ms = start.elapsed()
+            .map(|duration| { duration.as_millis() })
+            .unwrap_or(0);
+        return match result {
+            Ok(published) => self.classify(published, elapsed_ms),
+            Err(_) => {
+                *self.channel.lock().await = None;
+                warn!("Publishing to amqp sink {} of route {} timed out after {}ms!", self.name, self.route, elapsed_ms);
+                ForwardResult::Retryable {
+                    reason: format!("Publish was not confirmed within {}ms", elapsed_ms),
+                    retry_after: None,
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lapin::protocol::{AMQPError, AMQPErrorKind, AMQPHardError};
+
+    fn classify(published: Result<Published, lapin::Error>) -> ForwardResult {
+        return AmqpSink::new("default", "events").classify(published, 5);
+    }
+
+    #[test]
+    fn delivers_acked_message() {
+        assert!(matches!(classify(Ok(Published::from(Confirmation::Ack(None)))), ForwardResult::Delivered));
+    }
+
+    #[test]
+    fn retries_returned_message() {
+        let result = classify(Ok(Published::Returned { reply_text: "NO_ROUTE".to_owned() }));
+        assert!(matches!(result, ForwardResult::Retryable { reason, retry_after: None }
+            if reason == "Message was returned unroutable: NO_ROUTE"));
+    }
+
+    #[test]
+    fn retries_nacked_message() {
+        let result = classify(Ok(Published::from(Confirmation::Nack(None))));
+        assert!(matches!(result, ForwardResult::Retryable { reason, .. } if reason == "Broker nacked the message"));
+    }
+
+    #[test]
+    fn retries_unconfirmed_message() {
+        let result = classify(Ok(Published::from(Confirmation::NotRequested)));
+        assert!(matches!(result, ForwardResult::Retryable { .. }));
+    }
+
+    #[test]
+    fn holds_votes_refused_access() {
+        let refused = AMQPError::from_id(REPLY_CODE_ACCESS_REFUSED, "ACCESS_REFUSED".into()).unwrap();
+        assert!(matches!(classify(Err(lapin::Error::ProtocolError(refused))), ForwardResult::Unauthorized { .. }));
+    }
+
+    #[test]
+    fn retries_other_errors() {
+        let error = AMQPError::new(AMQPErrorKind::Hard(AMQPHardError::CONNECTIONFORCED), "closed".into());
+        let result = classify(Err(lapin::Error::ProtocolError(error)));
+        assert!(matches!(result, ForwardResult::Retryable { reason, .. } if reason.starts_with("Publish failed after 5ms")));
+        assert!(matches!(classify(Err(lapin::Error::InvalidChannelState(lapin::ChannelState::Closed))),
+                         ForwardResult::Retryable { .. }));
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::net::SocketAddr;
+use log::warn;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use warp::{Filter, Rejection};
+use crate::metrics;
+
+pub const REASON_MISSING_HEADER: &str = "missing_header";
+pub const REASON_WRONG_TOKEN: &str = "wrong_token";
+pub const REASON_EXPIRED_TOKEN: &str = "expired_token";
+pub const REASON_BAD_JWT: &str = "bad_jwt";
+pub const REASON_BAD_SIGNATURE: &str = "bad_signature";
+
+/**
+Why a vote request failed authorization
+*/
+#[derive(Debug)]
+pub enum AuthFailure {
+    MissingHeader,
+    WrongToken,
+    ExpiredToken { id: String },
+    BadJwt { error: String },
+    BadSignature { error: String },
+}
+
+impl AuthFailure {
+    /**
+    Label of the failure in logs and metrics
+    */
+    pub fn reason(&self) -> &'static str {
+        return match self {
+            AuthFailure::MissingHeader => REASON_MISSING_HEADER,
+            AuthFailure::WrongToken => REASON_WRONG_TOKEN,
+            AuthFailure::ExpiredToken { .. } => REASON_EXPIRED_TOKEN,
+            AuthFailure::BadJwt { .. } => REASON_BAD_JWT,
+            AuthFailure::BadSignature { .. } => REASON_BAD_SIGNATURE,
+        };
+    }
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            AuthFailure::MissingHeader => write!(f, "no Authorization header"),
+            AuthFailure::WrongToken => write!(f, "no token matched"),
+            AuthFailure::ExpiredToken { id } => write!(f, "token {} expired", id),
+            AuthFailure::BadJwt { error } => write!(f, "JWT verification failed: {}", error),
+            AuthFailure::BadSignature { error } => write!(f, "signature verification failed: {}", error),
+        };
+    }
+}
+
+/**
+The client of a request as far as it is known, for auth failure events
+*/
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub remote: Option<SocketAddr>,
+    pub forwarded_for: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/**
+Extracts the client of a request
+*/
+pub fn client_info() -> impl Filter<Extract=(ClientInfo,), Error=Rejection> + Clone {
+    return warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(|remote: Option<SocketAddr>, forwarded_for: Option<String>, user_agent: Option<String>| {
+            ClientInfo { remote, forwarded_for, user_agent }
+        });
+}
+
+/**
+Logs an auth failure as structured event and counts it by source and reason
+*/
+pub fn report(source: &str, failure: &AuthFailure, client: &ClientInfo) {
+    warn!(target: "auth", "Dropping unauthorized request: source={} reason={} ip={} forwarded_for={:?} \
+                           user_agent={:?} detail={:?}",
+          source, failure.reason(),
+          client.remote.map(|remote| remote.ip().to_string()).unwrap_or_else(|| "unknown".to_owned()),
+          client.forwarded_for.as_deref().unwrap_or(""), client.user_agent.as_deref().unwrap_or(""),
+          failure.to_string());
+    metrics::count_unauthorized(source, failure.reason());
+}
+
+/**
+Compares two secrets in constant time, hashing both first so neither the position of the first
+difference nor the length of the expected secret leaks
+*/
+pub fn secrets_equal(provided: &str, expected: &str) -> bool {
+    let provided = Sha256::digest(provided.as_bytes());
+    let expected = Sha256::digest(expected.as_bytes());
+    return provided.as_slice().ct_eq(expected.as_slice()).into();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_of_different_length_are_not_equal() {
+        assert!(secrets_equal("secret", "secret"));
+        assert!(!secrets_equal("secret", "secret2"));
+        assert!(!secrets_equal("secret2", "secret"));
+        assert!(!secrets_equal("", "secret"));
+        assert!(!secrets_equal("secret", ""));
+        assert!(secrets_equal("", ""));
+        assert!(!secrets_equal("secreT", "secret"));
+    }
+}
@@ -0,0 +1,329 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use hmac::digest::KeyInit;
+use hmac::Hmac;
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use toml::value::{Datetime, Offset};
+use toml::{Table, Value};
+use crate::auth_failure::{secrets_equal, AuthFailure};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/**
+A secret accepted for a source, identified by its id in logs and metrics
+*/
+#[derive(Clone, Debug)]
+pub struct AuthKey {
+    pub id: String,
+    secret: String,
+    expires: Option<Datetime>,
+    expires_at: Option<u64>,
+}
+
+impl AuthKey {
+    fn new(id: String, secret: String, expires: Option<Datetime>) -> Result<AuthKey, String> {
+        if secret.is_empty() {
+            return Err("secrets must not be empty".to_owned());
+        }
+        let expires_at = match expires.as_ref() {
+            Some(expires) => Some(unix_seconds(expires)?),
+            None => None,
+        };
+        return Ok(AuthKey { id, secret, expires, expires_at });
+    }
+
+    pub fn is_expired(&self, now_secs: u64) -> bool {
+        return self.expires_at.map(|expires_at| now_secs >= expires_at).unwrap_or(false);
+    }
+
+    /**
+    The key used to sign JWT tokens with this secret
+    */
+    pub fn hmac(&self) -> Hmac<Sha256> {
+        return Hmac::new_from_slice(self.secret.as_bytes()).unwrap();
+    }
+
+    /**
+    The secret itself, for signature schemes deriving their keys from it
+    */
+    pub fn secret_bytes(&self) -> &[u8] {
+        return self.secret.as_bytes();
+    }
+}
+
+/**
+The secrets accepted for a source. Configured as a string of comma separated secrets, each optionally
+followed by `@<expiry>`, or as an array of secrets and `{ secret, id, expires }` tables.
+*/
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub struct AuthKeys(Vec<AuthKey>);
+
+impl AuthKeys {
+    pub fn single(secret: &str) -> AuthKeys {
+        return AuthKeys(vec![AuthKey::new("0".to_owned(), secret.to_owned(), None).unwrap()]);
+    }
+
+    /**
+    No keys at all, accepting no request. Only a placeholder until the configured keys are loaded
+    */
+    pub fn none() -> AuthKeys {
+        return AuthKeys(Vec::new());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.0.is_empty();
+    }
+
+    /**
+    The unexpired key equal to the provided secret, or why none matched. Every key is compared in
+    constant time and the loop doesn't stop at a match, so the timing doesn't reveal which key
+    matched. It does grow with the number of configured keys
+    */
+    pub fn verify(&self, provided: &str, now_secs: u64) -> Result<&AuthKey, AuthFailure> {
+        let mut matched = None;
+        for key in self.0.iter() {
+            if secrets_equal(provided, key.secret.as_str()) && matched.is_none() {
+                matched = Some(key);
+            }
+        }
+        return match matched {
+            Some(key) if key.is_expired(now_secs) => Err(AuthFailure::ExpiredToken { id: key.id.clone() }),
+            Some(key) => Ok(key),
+            None => Err(AuthFailure::WrongToken),
+        };
+    }
+
+    /**
+    The unexpired keys, in the configured order
+    */
+    pub fn active(&self, now_secs: u64) -> impl Iterator<Item=&AuthKey> {
+        return self.0.iter().filter(move |key| !key.is_expired(now_secs));
+    }
+
+    /**
+    The keys with their secrets replaced, for printing
+    */
+    pub fn redacted(&self, replacement: &str) -> AuthKeys {
+        return AuthKeys(self.0.iter()
+            .map(|key| AuthKey { secret: replacement.to_owned(), ..key.clone() })
+            .collect());
+    }
+
+    /**
+    Secret of the first key, used where a single secret is sent rather than verified
+    */
+    pub fn primary_secret(&self) -> &str {
+        return self.0[0].secret.as_str();
+    }
+
+    /**
+    Ids given to more than one key, each listed once in the order they first repeat
+    */
+    pub fn duplicate_ids(&self) -> Vec<&str> {
+        let mut duplicates: Vec<&str> = Vec::new();
+        for (index, key) in self.0.iter().enumerate() {
+            let repeated = self.0[..index].iter().any(|other| other.id == key.id);
+            if repeated && !duplicates.contains(&key.id.as_str()) {
+                duplicates.push(key.id.as_str());
+            }
+        }
+        return duplicates;
+    }
+}
+
+impl TryFrom<Value> for AuthKeys {
+    type Error = String;
+
+    fn try_from(value: Value) -> Result<AuthKeys, String> {
+        let keys = match value {
+            Value::String(value) => value.split(',')
+                .map(|entry| entry.trim())
+                .filter(|entry| !entry.is_empty())
+                .enumerate()
+                .map(|(index, entry)| parse_entry(index, entry))
+                .collect::<Result<Vec<AuthKey>, String>>()?,
+            Value::Array(entries) => entries.into_iter()
+                .enumerate()
+                .map(|(index, entry)| match entry {
+                    Value::String(entry) => parse_entry(index, entry.as_str()),
+                    Value::Table(table) => parse_table(index, table),
+                    other => Err(format!("expected a secret or a table, got {}", other)),
+                })
+                .collect::<Result<Vec<AuthKey>, String>>()?,
+            other => return Err(format!("expected secrets, got {}", other)),
+        };
+        if keys.is_empty() {
+            return Err("at least one secret is required".to_owned());
+        }
+        return Ok(AuthKeys(keys));
+    }
+}
+
+impl From<AuthKeys> for Value {
+    fn from(keys: AuthKeys) -> Value {
+        return Value::Array(keys.0.into_iter()
+            .map(|key| {
+                let mut table = Table::new();
+                table.insert("id".to_owned(), Value::String(key.id));
+                table.insert("secret".to_owned(), Value::String(key.secret));
+                if let Some(expires) = key.expires {
+                    table.insert("expires".to_owned(), Value::Datetime(expires));
+                }
+                Value::Table(table)
+            })
+            .collect());
+    }
+}
+
+/**
+A secret optionally followed by `@<expiry>`, the `@` is part of the secret if no valid date follows it.
+Keys without an id are named by their position, so nothing derived from the secret ends up in metrics
+*/
+fn parse_entry(index: usize, entry: &str) -> Result<AuthKey, String> {
+    if let Some((secret, expires)) = entry.rsplit_once('@') {
+        if let Ok(expires) = Datetime::from_str(expires) {
+            return AuthKey::new(index.to_string(), secret.to_owned(), Some(expires));
+        }
+    }
+    return AuthKey::new(index.to_string(), entry.to_owned(), None);
+}
+
+fn parse_table(index: usize, mut table: Table) -> Result<AuthKey, String> {
+    let secret = match table.remove("secret") {
+        Some(Value::String(secret)) => secret,
+        Some(other) => return Err(format!("secret must be a string, got {}", other)),
+        None => return Err("secret is missing".to_owned()),
+    };
+    let id = match table.remove("id") {
+        Some(Value::String(id)) => id,
+        Some(other) => return Err(format!("id must be a string, got {}", other)),
+        None => index.to_string(),
+    };
+    let expires = match table.remove("expires") {
+        Some(Value::Datetime(expires)) => Some(expires),
+        Some(Value::String(expires)) => Some(Datetime::from_str(expires.as_str())
+            .map_err(|err| format!("expires is not a valid date ({}): {}", err, expires))?),
+        Some(other) => return Err(format!("expires must be a date, got {}", other)),
+        None => None,
+    };
+    if let Some(key) = table.keys().next() {
+        return Err(format!("unknown field `{}`, expected secret, id or expires", key));
+    }
+    return AuthKey::new(id, secret, expires);
+}
+
+/**
+Seconds since the unix epoch, dates without a time mean midnight and dates without an offset UTC
+*/
+fn unix_seconds(datetime: &Datetime) -> Result<u64, String> {
+    let date = datetime.date.ok_or_else(|| format!("expiry {} has no date", datetime))?;
+    let (year, month, day) = (date.year as i64, date.month as i64, date.day as i64);
+    // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+    let shifted_year = if month <= 2 { year - 1 } else { year };
+    let era = shifted_year.div_euclid(400);
+    let year_of_era = shifted_year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let mut seconds = days * SECONDS_PER_DAY;
+    if let Some(time) = datetime.time {
+        seconds += time.hour as i64 * 3600 + time.minute as i64 * 60 + time.second as i64;
+    }
+    if let Some(Offset::Custom { minutes }) = datetime.offset {
+        seconds -= minutes as i64 * 60;
+    }
+    return u64::try_from(seconds).map_err(|_| format!("expiry {} is before 1970", datetime));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(value: &str) -> Datetime {
+        return Datetime::from_str(value).unwrap();
+    }
+
+    fn keys(config: &str) -> Result<AuthKeys, String> {
+        let table: Table = toml::from_str(format!("keys = {}", config).as_str()).unwrap();
+        return AuthKeys::try_from(table["keys"].clone());
+    }
+
+    #[test]
+    fn expiry_is_converted_to_unix_seconds() {
+        assert_eq!(unix_seconds(&datetime("1970-01-01T00:00:00Z")), Ok(0));
+        assert_eq!(unix_seconds(&datetime("2024-01-01T00:00:00Z")), Ok(1_704_067_200));
+        assert_eq!(unix_seconds(&datetime("2000-02-29T12:30:15Z")), Ok(951_827_415));
+        // a date without a time means midnight, a time without an offset UTC
+        assert_eq!(unix_seconds(&datetime("2024-01-01")), Ok(1_704_067_200));
+        assert_eq!(unix_seconds(&datetime("2024-01-01T00:00:00")), Ok(1_704_067_200));
+        assert_eq!(unix_seconds(&datetime("2024-01-01T02:00:00+02:00")), Ok(1_704_067_200));
+        assert_eq!(unix_seconds(&datetime("2023-12-31T19:00:00-05:00")), Ok(1_704_067_200));
+    }
+
+    #[test]
+    fn expiry_before_1970_is_refused() {
+        assert_eq!(unix_seconds(&datetime("1969-12-31T23:59:59Z")),
+                   Err("expiry 1969-12-31T23:59:59Z is before 1970".to_owned()));
+        assert!(keys(r#""secret@1969-12-31""#).is_err());
+        assert!(unix_seconds(&datetime("12:00:00")).is_err());
+    }
+
+    #[test]
+    fn entry_keeps_at_sign_of_secret() {
+        let key = parse_entry(0, "p@ss@2030-01-01").unwrap();
+        assert_eq!(key.secret, "p@ss");
+        assert_eq!(key.expires_at, Some(1_893_456_000));
+
+        let key = parse_entry(1, "p@ss@word").unwrap();
+        assert_eq!(key.secret, "p@ss@word");
+        assert_eq!(key.expires_at, None);
+        assert_eq!(key.id, "1");
+
+        assert!(parse_entry(0, "@2030-01-01").is_err());
+    }
+
+    #[test]
+    fn table_with_unknown_field_is_refused() {
+        assert_eq!(keys(r#"[{ secret = "a", id = "ci", expiry = 2030-01-01 }]"#).unwrap_err(),
+                   "unknown field `expiry`, expected secret, id or expires");
+        assert_eq!(keys(r#"[{ id = "ci" }]"#).unwrap_err(), "secret is missing");
+        assert!(keys(r#"[{ secret = "a", expires = "soon" }]"#).is_err());
+
+        let keys = keys(r#"["a", { secret = "b", id = "ci", expires = "2030-01-01" }]"#).unwrap();
+        assert_eq!(keys.0[0].id, "0");
+        assert_eq!(keys.0[1].id, "ci");
+        assert_eq!(keys.0[1].expires_at, Some(1_893_456_000));
+    }
+
+    #[test]
+    fn duplicate_ids_are_listed_once() {
+        // the second secret is named 1 by its position, like the explicitly named fourth one
+        let keys = keys(r#"[{ secret = "a", id = "ci" }, "b", { secret = "c", id = "ci" }, { secret = "d", id = "1" },
+                           { secret = "e", id = "ci" }]"#).unwrap();
+        assert_eq!(keys.duplicate_ids(), vec!["ci", "1"]);
+        assert!(AuthKeys::single("a").duplicate_ids().is_empty());
+    }
+
+    #[test]
+    fn verify_tells_expired_from_wrong_secret() {
+        let keys = keys(r#"[{ secret = "old", id = "old", expires = 2024-01-01 }, { secret = "new", id = "new" }]"#)
+            .unwrap();
+        let before_expiry = 1_704_067_199;
+        let after_expiry = 1_704_067_200;
+
+        assert_eq!(keys.verify("old", before_expiry).unwrap().id, "old");
+        assert!(matches!(keys.verify("old", after_expiry), Err(AuthFailure::ExpiredToken { id }) if id == "old"));
+        assert_eq!(keys.verify("new", after_expiry).unwrap().id, "new");
+        assert!(matches!(keys.verify("other", before_expiry), Err(AuthFailure::WrongToken)));
+        assert!(matches!(keys.verify("", before_expiry), Err(AuthFailure::WrongToken)));
+        assert!(matches!(AuthKeys::none().verify("new", before_expiry), Err(AuthFailure::WrongToken)));
+    }
+
+    #[test]
+    fn verify_picks_first_of_equal_secrets() {
+        let keys = keys(r#"[{ secret = "same", id = "first" }, { secret = "same", id = "second" }]"#).unwrap();
+        assert_eq!(keys.verify("same", 0).unwrap().id, "first");
+    }
+}
@@ -1,28 +1,124 @@
+use serde_json::Value;
+use tokio::sync::oneshot;
 use crate::vote_request::VoteRequest;
-use crate::constants::{CACHE_TASK_OP_RESEND, CACHE_TASK_OP_VOTE};
+use crate::constants::{CACHE_TASK_OP_RESEND, CACHE_TASK_OP_VOTE, CACHE_TASK_OP_LIST_DEAD_LETTERS,
+                       CACHE_TASK_OP_REQUEUE_DEAD_LETTER, CACHE_TASK_OP_PURGE_DEAD_LETTERS,
+                       CACHE_TASK_OP_LIST_QUEUE, CACHE_TASK_OP_GET_QUEUED,
+                       CACHE_TASK_OP_DELETE_QUEUED, CACHE_TASK_OP_FORCE_RESEND, CACHE_TASK_OP_PAUSE_RESEND,
+                       CACHE_TASK_OP_RESUME_RESEND};
+use crate::vote_cache::QueueFilter;
+use crate::vote_handler::QueueSelector;
 
 pub struct CacheTask {
     pub op: u8,
-    pub auth: Option<String>,
     pub vote: Option<VoteRequest>,
-    pub msg: Option<String>,
+    pub id: Option<u64>,
+    pub selector: QueueSelector,
+    pub offset: usize,
+    pub limit: usize,
+    pub filter: QueueFilter,
+    pub reply: Option<oneshot::Sender<Value>>,
 }
 
 impl CacheTask {
-    pub fn create_vote_task(auth: String, vote: VoteRequest) -> CacheTask {
+    pub fn create_vote_task(vote: VoteRequest) -> CacheTask {
         return CacheTask {
-            op: CACHE_TASK_OP_VOTE,
-            auth: Some(auth),
             vote: Some(vote),
-            msg: None,
+            ..CacheTask::empty(CACHE_TASK_OP_VOTE)
         };
     }
     pub fn create_resend_task() -> CacheTask {
+        return CacheTask::empty(CACHE_TASK_OP_RESEND);
+    }
+    pub fn create_list_dead_letters_task(selector: QueueSelector, offset: usize, limit: usize,
+                                         reply: oneshot::Sender<Value>) -> CacheTask {
+        return CacheTask {
+            selector,
+            offset,
+            limit,
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_LIST_DEAD_LETTERS)
+        };
+    }
+    pub fn create_requeue_dead_letter_task(selector: QueueSelector, id: u64,
+                                           reply: oneshot::Sender<Value>) -> CacheTask {
         return CacheTask {
-            op: CACHE_TASK_OP_RESEND,
-            auth: None,
+            selector,
+            id: Some(id),
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_REQUEUE_DEAD_LETTER)
+        };
+    }
+    pub fn create_purge_dead_letters_task(selector: QueueSelector, id: Option<u64>,
+                                          reply: oneshot::Sender<Value>) -> CacheTask {
+        return CacheTask {
+            selector,
+            id,
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_PURGE_DEAD_LETTERS)
+        };
+    }
+    pub fn create_list_queue_task(selector: QueueSelector, filter: QueueFilter, offset: usize, limit: usize,
+                                  reply: oneshot::Sender<Value>) -> CacheTask {
+        return CacheTask {
+            selector,
+            filter,
+            offset,
+            limit,
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_LIST_QUEUE)
+        };
+    }
+    pub fn create_get_queued_task(selector: QueueSelector, id: u64, reply: oneshot::Sender<Value>) -> CacheTask {
+        return CacheTask {
+            selector,
+            id: Some(id),
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_GET_QUEUED)
+        };
+    }
+    pub fn create_delete_queued_task(selector: QueueSelector, id: u64, reply: oneshot::Sender<Value>) -> CacheTask {
+        return CacheTask {
+            selector,
+            id: Some(id),
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_DELETE_QUEUED)
+        };
+    }
+    pub fn create_force_resend_task(selector: QueueSelector, reply: oneshot::Sender<Value>) -> CacheTask {
+        return CacheTask {
+            selector,
+            reply: Some(reply),
+            ..CacheTask::empty(CACHE_TASK_OP_FORCE_RESEND)
+        };
+    }
+    pub fn create_pause_resend_task(paused: bool, reply: oneshot::Sender<Value>) -> CacheTask {
+        let op = if paused { CACHE_TASK_OP_PAUSE_RESEND } else { CACHE_TASK_OP_RESUME_RESEND };
+        return CacheTask {
+            reply: Some(reply),
+            ..CacheTask::empty(op)
+        };
+    }
+
+    /**
+    Sends the result of the task back to the rest endpoint waiting for it
+    */
+    pub fn reply(&mut self, value: Value) {
+        if let Some(reply) = self.reply.take() {
+            let _ = reply.send(value);
+        }
+    }
+
+    fn empty(op: u8) -> CacheTask {
+        return CacheTask {
+            op,
             vote: None,
-            msg: None,
+            id: None,
+            selector: QueueSelector::default(),
+            offset: 0,
+            limit: 0,
+            filter: QueueFilter::default(),
+            reply: None,
         };
     }
-}
\ No newline at end of file
+}
@@ -1,77 +1,40 @@
-use std::env::var;
-use core::time::Duration;
-use hmac::digest::KeyInit;
-use hmac::Hmac;
-use lazy_static::lazy_static;
-use sha2::Sha256;
-
-lazy_static! {
-    /**
-    Endpoint to use for proxied requests
-    */
-    pub static ref VOTE_ENDPOINT: String = var("VOTE_ENDPOINT")
-        .unwrap_or("http//api.server.dev/vote".to_owned());
-
-    /**
-    Authorization token provided to the endpoint in Authorization header
-    */
-    pub static ref VOTE_ENDPOINT_AUTH_TOKEN: String = var("VOTE_ENDPOINT_AUTH_TOKEN")
-        .unwrap_or(var("VOTE_AUTH_TOKEN").unwrap_or("secret".to_owned()));
-
-    /**
-    Delay in seconds between each resend execution, - executed per instance
-    */
-    pub static ref VOTE_RESEND_DELAY: Duration = Duration::from_secs(
-        var("VOTE_RESEND_DELAY").unwrap_or("5".to_owned()).parse().unwrap_or(5));
-
-    /**
-    Max amount of resent votes per resend-execution
-    */
-    pub static ref VOTE_RESEND_BULK_COUNT: u32 = var("VOTE_RESEND_BULK_COUNT")
-        .unwrap_or("100".to_owned())
-        .parse()
-        .unwrap_or(100);
-
-
-    /**
-    Authorization token provided in the Authorization header for vote/generic endpoint
-    */
-    pub static ref VOTE_AUTH_TOKEN: String = var("VOTE_AUTH_TOKEN")
-        .unwrap_or("secret".to_owned());
-    /**
-    Authorization token provided in the Authorization header for vote/topgg endpoint
-    */
-    pub static ref VOTE_AUTH_TOKEN_TOPGG: String = var("VOTE_AUTH_TOKEN_TOPGG")
-        .unwrap_or(VOTE_AUTH_TOKEN.clone());
-    /**
-    Authorization token provided in the Authorization header for vote/dbl endpoint
-    */
-    pub static ref VOTE_AUTH_TOKEN_DBL: String = var("VOTE_AUTH_TOKEN_DBL")
-        .unwrap_or(VOTE_AUTH_TOKEN.clone());
-    /**
-    Authorization token provided in the Authorization header for vote/bfd endpoint
-    */
-    pub static ref VOTE_AUTH_TOKEN_BFD: String = var("VOTE_AUTH_TOKEN_BFD")
-        .unwrap_or(VOTE_AUTH_TOKEN.clone());
-    /**
-    Authorization token provided in the Authorization header for vote/dboats endpoint
-    */
-    pub static ref VOTE_AUTH_TOKEN_DBOATS: String = var("VOTE_AUTH_TOKEN_DBOATS")
-        .unwrap_or(VOTE_AUTH_TOKEN.clone());
-    /**
-    The token (as string) provided to sign JWT tokens for dlist request bodies on the vote/dlist endpoint
-    */
-    pub static ref VOTE_AUTH_TOKEN_DLIST: String = var("VOTE_AUTH_TOKEN_DLIST").unwrap_or(VOTE_AUTH_TOKEN.clone());
-    /**
-    The key provided to sign JWT tokens for dlist request bodies on the vote/dlist endpoint
-     */
-    pub static ref VOTE_AUTH_KEY_DLIST: Hmac<Sha256> = Hmac::new_from_slice(VOTE_AUTH_TOKEN_DLIST.as_bytes()).unwrap();
-}
-
 pub const CACHE_TASK_OP_VOTE: u8 = 0;
 pub const CACHE_TASK_OP_RESEND: u8 = 1;
+pub const CACHE_TASK_OP_LIST_DEAD_LETTERS: u8 = 2;
+pub const CACHE_TASK_OP_REQUEUE_DEAD_LETTER: u8 = 3;
+pub const CACHE_TASK_OP_PURGE_DEAD_LETTERS: u8 = 4;
+pub const CACHE_TASK_OP_LIST_QUEUE: u8 = 7;
+pub const CACHE_TASK_OP_GET_QUEUED: u8 = 8;
+pub const CACHE_TASK_OP_DELETE_QUEUED: u8 = 9;
+pub const CACHE_TASK_OP_FORCE_RESEND: u8 = 10;
+pub const CACHE_TASK_OP_PAUSE_RESEND: u8 = 11;
+pub const CACHE_TASK_OP_RESUME_RESEND: u8 = 12;
+pub const CACHE_BACKEND_MEMORY: &str = "memory";
+pub const CACHE_BACKEND_WAL: &str = "wal";
+pub const CACHE_BACKEND_SQLITE: &str = "sqlite";
+pub const CACHE_BACKEND_REDIS: &str = "redis";
+pub const DEFAULT_ROUTE: &str = "default";
+pub const PRIMARY_SINK: &str = "endpoint";
+pub const SINK_KIND_HTTP: &str = "http";
+pub const SINK_KIND_DISCORD: &str = "discord";
+pub const SINK_KIND_AMQP: &str = "amqp";
+pub const SINK_KIND_KAFKA: &str = "kafka";
+pub const SINK_KIND_NATS: &str = "nats";
+pub const SINK_KIND_GRPC: &str = "grpc";
+pub const VOTE_KIND_BOT: &str = "bot";
+pub const VOTE_KIND_GUILD: &str = "guild";
+pub const PAGE_KEY_GENERIC: &str = "generic";
 pub const PAGE_KEY_TOPGG: &str = "topgg";
 pub const PAGE_KEY_DBL: &str = "dbl";
 pub const PAGE_KEY_BFD: &str = "bfd";
 pub const PAGE_KEY_DBOATS: &str = "dboats";
+pub const PAGE_KEY_DBLCOM: &str = "dblcom";
+pub const PAGE_KEY_IBL: &str = "ibl";
+pub const PAGE_KEY_VOIDBOTS: &str = "voidbots";
+pub const PAGE_KEY_DLABS: &str = "dlabs";
+pub const PAGE_KEY_BOTLISTME: &str = "botlistme";
+/**
+Source of failed admin requests in auth failure events and metrics
+*/
+pub const AUTH_SOURCE_ADMIN: &str = "admin";
 pub const PAGE_KEY_DLIST: &str = "dlist";
\ No newline at end of file
@@ -0,0 +1,260 @@
+use async_trait::async_trait;
+use core::time::Duration;
+use log::{debug, warn};
+use reqwest::Client;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+use crate::config::{self, DiscordTemplate, SinkConfig};
+use crate::forward_result::{ForwardResult, parse_retry_after};
+use crate::vote_request::VoteRequest;
+use crate::vote_sink::VoteSink;
+
+const HEADER_RATE_LIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const HEADER_RATE_LIMIT_RESET_AFTER: &str = "x-ratelimit-reset-after";
+
+#[derive(Debug, Deserialize)]
+struct RateLimitResponse {
+    retry_after: f64,
+    #[serde(default)]
+    global: bool,
+}
+
+/**
+Posts a message rendered from the template of the sink to a Discord webhook. Votes are held back
+while the rate limit of the webhook is used up instead of provoking a 429 response.
+*/
+pub struct DiscordSink {
+    http_client: Client,
+    route: String,
+    name: String,
+    blocked_until: Mutex<Option<Instant>>,
+}
+
+impl DiscordSink {
+    pub fn new(http_client: Client, route: &str, name: &str) -> DiscordSink {
+        return DiscordSink {
+            http_client,
+            route: route.to_owned(),
+            name: name.to_owned(),
+            blocked_until: Mutex::new(None),
+        };
+    }
+
+    /**
+    The time left until the rate limit resets, None if requests can be made
+    */
+    fn rate_limited_for(&self) -> Option<Duration> {
+        let blocked_until = (*self.blocked_until.lock().unwrap())?;
+        return blocked_until.checked_duration_since(Instant::now())
+            .filter(|remaining| !remaining.is_zero());
+    }
+
+    fn block_for(&self, delay: Duration) {
+        debug!("Discord sink {} of route {} is rate limited for {}ms", self.name, self.route, delay.as_millis());
+        *self.blocked_until.lock().unwrap() = Some(Instant::now() + delay);
+    }
+
+    /**
+    Remembers an exhausted rate limit bucket announced by the headers of a response
+    */
+    fn track_rate_limit(&self, headers: &HeaderMap) {
+        let remaining = header_f64(headers, HEADER_RATE_LIMIT_REMAINING);
+        let reset_after = header_f64(headers, HEADER_RATE_LIMIT_RESET_AFTER);
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            if remaining < 1.0 {
+                self.block_for(Duration::from_secs_f64(reset_after.max(0.0)));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl VoteSink for DiscordSink {
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+        if let Some(delay) = self.rate_limited_for() {
+            return ForwardResult::Retryable {
+                reason: format!("Webhook is rate limited for {}ms", delay.as_millis()),
+                retry_after: Some(delay),
+            };
+        }
+        let config = config::get();
+        let sink = match config.sink(self.route.as_str(), self.name.as_str()) {
+            Some(sink) => sink,
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} is not configured", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let (endpoint, template) = match sink.endpoint.as_deref() {
+            Some(endpoint) => (endpoint, template_for(sink, vote)),
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} has no webhook", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let start = SystemTime::now();
+        let response = self.http_client.post(endpoint)
+            .timeout(config.sink_timeout())
+            .header(CONTENT_TYPE, "application/json")
+            .body(render_message(&template, vote).to_string())
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                let elapsed_ms = start.elapsed()
+                    .map(|duration| { duration.as_millis() })
+                    .unwrap_or(0);
+                warn!("Request to discord sink {} of route {} failed after {}ms!", self.name, self.route, elapsed_ms);
+                return ForwardResult::Retryable {
+                    reason: format!("Request failed after {}ms: {}", elapsed_ms, err),
+                    retry_after: None,
+                };
+            }
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        self.track_rate_limit(&headers);
+        if status.is_success() {
+            return ForwardResult::Delivered;
+        }
+        let body = response.text().await.unwrap_or_default();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            let delay = serde_json::from_str::<RateLimitResponse>(body.as_str()).ok()
+                .map(|limit| {
+                    if limit.global {
+                        warn!("Discord sink {} of route {} hit the global rate limit", self.name, self.route);
+                    }
+                    Duration::from_secs_f64(limit.retry_after.max(0.0))
+                })
+                .or_else(|| parse_retry_after(&headers))
+                .unwrap_or(Duration::from_secs(1));
+            self.block_for(delay);
+            return ForwardResult::Retryable {
+                reason: format!("Webhook responded with {}", status),
+                retry_after: Some(delay),
+            };
+        }
+        warn!("Discord sink {} of route {} responded with {}", self.name, self.route, status);
+        return ForwardResult::from_http_error(status, &headers, body.as_str());
+    }
+}
+
+/**
+The template of the sink for the kind of vote, or the default one
+*/
+fn template_for(sink: &SinkConfig, vote: &VoteRequest) -> DiscordTemplate {
+    if vote.guild.is_some() {
+        return sink.guild_template.clone().unwrap_or_else(DiscordTemplate::guild_default);
+    }
+    return sink.template.clone().unwrap_or_default();
+}
+
+/**
+Builds the webhook payload, only the voter may be pinged by it
+*/
+fn render_message(template: &DiscordTemplate, vote: &VoteRequest) -> Value {
+    let render = |text: &Option<String>| text.as_deref().map(|text| render_text(text, vote));
+    let mut message = Map::new();
+    if let Some(username) = render(&template.username) {
+        message.insert("username".to_owned(), Value::String(username));
+    }
+    if let Some(avatar_url) = template.avatar_url.as_ref() {
+        message.insert("avatar_url".to_owned(), Value::String(avatar_url.clone()));
+    }
+    if let Some(content) = render(&template.content) {
+        message.insert("content".to_owned(), Value::String(content));
+    }
+    let mut embed = Map::new();
+    if let Some(title) = render(&template.title) {
+        embed.insert("title".to_owned(), Value::String(title));
+    }
+    if let Some(description) = render(&template.description) {
+        embed.insert("description".to_owned(), Value::String(description));
+    }
+    if let Some(footer) = render(&template.footer) {
+        embed.insert("footer".to_owned(), json!({ "text": footer }));
+    }
+    if !embed.is_empty() {
+        if let Some(color) = template.color {
+            embed.insert("color".to_owned(), json!(color));
+        }
+        message.insert("embeds".to_owned(), json!([embed]));
+    }
+    message.insert("allowed_mentions".to_owned(), json!({ "users": [vote.user.0.to_string()] }));
+    return Value::Object(message);
+}
+
+fn render_text(text: &str, vote: &VoteRequest) -> String {
+    return text
+        .replace("{user_mention}", format!("<@{}>", vote.user.0).as_str())
+        .replace("{bot_mention}", format!("<@{}>", vote.bot.0).as_str())
+        .replace("{user}", vote.user.0.to_string().as_str())
+        .replace("{bot}", vote.bot.0.to_string().as_str())
+        .replace("{guild}", vote.guild.map(|guild| guild.0.to_string()).unwrap_or_default().as_str())
+        .replace("{kind}", vote.kind.as_str())
+        .replace("{source}", vote.src.as_deref().unwrap_or("unknown"))
+        .replace("{type}", vote.r#type.as_str())
+        .replace("{weekend}", if vote.is_weekend { "yes" } else { "no" });
+}
+
+fn header_f64(headers: &HeaderMap, name: &str) -> Option<f64> {
+    return headers.get(name)?.to_str().ok()?.trim().parse::<f64>().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(template: &str) -> SinkConfig {
+        let sink = format!("name = \"thanks\"\nkind = \"discord\"\nendpoint = \"https://discord.test/webhook\"\n{}", template);
+        return toml::from_str(sink.as_str()).unwrap();
+    }
+
+    fn vote(json: &str) -> VoteRequest {
+        return serde_json::from_str(json).unwrap();
+    }
+
+    const BOT_VOTE: &str = r#"{"bot":"1","user":"2","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}"#;
+    const GUILD_VOTE: &str = r#"{"bot":"0","user":"2","type":"upvote","isWeekend":true,"query":null,"src":"topgg","kind":"guild","guild":"3"}"#;
+
+    fn description(message: &Value) -> &str {
+        return message["embeds"][0]["description"].as_str().unwrap();
+    }
+
+    #[test]
+    fn renders_bot_vote_with_default_template() {
+        let vote = vote(BOT_VOTE);
+        let message = render_message(&template_for(&sink(""), &vote), &vote);
+
+        assert_eq!(description(&message), "<@2> voted for <@1> on topgg");
+        assert_eq!(message["allowed_mentions"]["users"], json!(["2"]));
+    }
+
+    #[test]
+    fn renders_guild_vote_with_guild_default_template() {
+        let vote = vote(GUILD_VOTE);
+        let message = render_message(&template_for(&sink(""), &vote), &vote);
+
+        assert_eq!(description(&message), "<@2> voted for the server 3 on topgg");
+        assert_eq!(message["embeds"][0]["footer"]["text"], "Type: upvote | Weekend: yes");
+    }
+
+    #[test]
+    fn renders_guild_vote_with_guild_template() {
+        let sink = sink("template = { content = \"{bot_mention}\" }\nguild_template = { content = \"{kind} {guild}\" }");
+        let guild_vote = vote(GUILD_VOTE);
+        let bot_vote = vote(BOT_VOTE);
+
+        assert_eq!(render_message(&template_for(&sink, &guild_vote), &guild_vote)["content"], "guild 3");
+        assert_eq!(render_message(&template_for(&sink, &bot_vote), &bot_vote)["content"], "<@1>");
+    }
+}
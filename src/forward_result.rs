@@ -0,0 +1,109 @@
+use core::time::Duration;
+use std::time::SystemTime;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+
+const STATUS_TOO_EARLY: u16 = 425;
+
+/**
+Outcome of a single attempt to deliver a vote downstream
+*/
+#[derive(Debug, Clone)]
+pub enum ForwardResult {
+    /**
+    The vote was accepted
+    */
+    Delivered,
+    /**
+    The vote could not be delivered right now, `retry_after` is the delay requested by the endpoint
+    */
+    Retryable { reason: String, retry_after: Option<Duration> },
+    /**
+    The endpoint refused the vote itself, retrying it won't change the outcome
+    */
+    Rejected { reason: String },
+    /**
+    The endpoint refused our credentials, the vote is retried once they are fixed
+    */
+    Unauthorized { reason: String },
+}
+
+impl ForwardResult {
+    /**
+    Classifies a non-successful http response by its status code. `404` and `405` point at a wrong
+    endpoint rather than a bad vote, so they are retried until the endpoint is fixed
+    */
+    pub fn from_http_error(status: StatusCode, headers: &HeaderMap, body: &str) -> ForwardResult {
+        let reason = format!("Endpoint responded with {}: {}", status, truncate(body, 200));
+        return match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ForwardResult::Unauthorized { reason },
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED | StatusCode::REQUEST_TIMEOUT
+            | StatusCode::TOO_MANY_REQUESTS => {
+                ForwardResult::Retryable { reason, retry_after: parse_retry_after(headers) }
+            }
+            status if status.is_server_error() || status.as_u16() == STATUS_TOO_EARLY => {
+                ForwardResult::Retryable { reason, retry_after: parse_retry_after(headers) }
+            }
+            _ => ForwardResult::Rejected { reason },
+        };
+    }
+}
+
+/**
+Reads the Retry-After header, given either in seconds or as http date
+*/
+pub fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    return Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO));
+}
+
+fn truncate(body: &str, max_chars: usize) -> &str {
+    return match body.char_indices().nth(max_chars) {
+        Some((index, _)) => &body[..index],
+        None => body,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn classify(status: u16) -> ForwardResult {
+        return ForwardResult::from_http_error(StatusCode::from_u16(status).unwrap(), &HeaderMap::new(), "");
+    }
+
+    #[test]
+    fn retries_unavailable_or_missing_endpoint() {
+        for status in [404, 405, 408, 425, 429, 500, 503] {
+            assert!(matches!(classify(status), ForwardResult::Retryable { retry_after: None, .. }), "{}", status);
+        }
+    }
+
+    #[test]
+    fn holds_votes_refused_for_credentials() {
+        for status in [401, 403] {
+            assert!(matches!(classify(status), ForwardResult::Unauthorized { .. }), "{}", status);
+        }
+    }
+
+    #[test]
+    fn rejects_other_client_errors() {
+        for status in [400, 409, 410, 413, 422] {
+            assert!(matches!(classify(status), ForwardResult::Rejected { .. }), "{}", status);
+        }
+    }
+
+    #[test]
+    fn reads_retry_after_seconds() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("30"));
+
+        let result = ForwardResult::from_http_error(StatusCode::TOO_MANY_REQUESTS, &headers, "slow down");
+        assert!(matches!(result, ForwardResult::Retryable { retry_after: Some(delay), .. } if delay == Duration::from_secs(30)));
+    }
+}
@@ -0,0 +1,202 @@
+use async_trait::async_trait;
+use log::{debug, warn};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tonic::{Code, Request, Status};
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use crate::config;
+use crate::forward_result::ForwardResult;
+use crate::vote_request::VoteRequest;
+use crate::vote_sink::VoteSink;
+
+pub mod proto {
+    tonic::include_proto!("vote");
+}
+
+use proto::vote_service_client::VoteServiceClient;
+
+/**
+Calls the unary `SubmitVote` rpc of proto/vote.proto, with the token of the endpoint or sink
+as `authorization` and the idempotency key as `idempotency-key` metadata
+*/
+pub struct GrpcSink {
+    route: String,
+    name: String,
+    channel: Mutex<Option<(String, Channel)>>,
+}
+
+impl GrpcSink {
+    pub fn new(route: &str, name: &str) -> GrpcSink {
+        return GrpcSink {
+            route: route.to_owned(),
+            name: name.to_owned(),
+            channel: Mutex::new(None),
+        };
+    }
+
+    /**
+    The channel to the endpoint, which connects on first use and reconnects on its own
+    */
+    fn channel(&self, endpoint: &str) -> Result<Channel, tonic::transport::Error> {
+        let mut current = self.channel.lock().unwrap();
+        if let Some((url, channel)) = current.as_ref() {
+            if url == endpoint {
+                return Ok(channel.clone());
+            }
+        }
+        let channel = create_endpoint(endpoint, config::get().sink_timeout())?.connect_lazy();
+        *current = Some((endpoint.to_owned(), channel.clone()));
+        return Ok(channel);
+    }
+}
+
+#[async_trait]
+impl VoteSink for GrpcSink {
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+        let config = config::get();
+        let (endpoint, auth_token) = match config.sink_target(self.route.as_str(), self.name.as_str()) {
+            Some(target) => target,
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} is not configured", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let channel = match self.channel(endpoint) {
+            Ok(channel) => channel,
+            Err(err) => {
+                return ForwardResult::Retryable { reason: format!("Invalid endpoint: {}", err), retry_after: None };
+            }
+        };
+        let mut request = Request::new(to_proto(vote));
+        request.set_timeout(config.sink_timeout());
+        if let Some(auth_token) = auth_token {
+            match MetadataValue::try_from(auth_token) {
+                Ok(value) => {
+                    request.metadata_mut().insert("authorization", value);
+                }
+                Err(_) => {
+                    return ForwardResult::Unauthorized {
+                        reason: "Auth token is not a valid metadata value".to_owned(),
+                    };
+                }
+            }
+        }
+        if let Some(key) = vote.idempotency_key.as_deref().and_then(|key| MetadataValue::try_from(key).ok()) {
+            request.metadata_mut().insert("idempotency-key", key);
+        }
+        let start = SystemTime::now();
+        return match VoteServiceClient::new(channel).submit_vote(request).await {
+            Ok(_) => ForwardResult::Delivered,
+            Err(status) => {
+                let elapsed_ms = start.elapsed()
+                    .map(|duration| { duration.as_millis() })
+                    .unwrap_or(0);
+                let code = status.code();
+                let result = classify_status(status);
+                if !matches!(result, ForwardResult::Delivered) {
+                    warn!("SubmitVote to {} of route {} failed after {}ms with {:?}", self.name, self.route, elapsed_ms,
+                          code);
+                }
+                result
+            }
+        };
+    }
+}
+
+/**
+Checks the endpoint accepts http/2 connections, as a grpc endpoint can't be probed with a plain http request
+*/
+pub async fn check_reachable(endpoint: &str, timeout: Duration) -> Result<(), String> {
+    let endpoint = create_endpoint(endpoint, timeout).map_err(|err| err.to_string())?;
+    return endpoint.connect().await
+        .map(|_| ())
+        .map_err(|err| err.to_string());
+}
+
+fn create_endpoint(endpoint: &str, timeout: Duration) -> Result<Endpoint, tonic::transport::Error> {
+    let mut builder = Endpoint::from_shared(endpoint.to_owned())?
+        .connect_timeout(timeout);
+    if endpoint.starts_with("https://") {
+        builder = builder.tls_config(ClientTlsConfig::new().with_native_roots())?;
+    }
+    return Ok(builder);
+}
+
+fn to_proto(vote: &VoteRequest) -> proto::VoteRequest {
+    return proto::VoteRequest {
+        bot: vote.bot.0,
+        user: vote.user.0,
+        r#type: vote.r#type.clone(),
+        is_weekend: vote.is_weekend,
+        query: vote.query.clone(),
+        src: vote.src.clone(),
+        idempotency_key: vote.idempotency_key.clone(),
+        received_at: vote.received_at,
+        kind: vote.kind.clone(),
+        guild: vote.guild.map(|guild| guild.0),
+        avatar: vote.avatar.clone(),
+        admin: vote.admin,
+    };
+}
+
+/**
+Classifies a failed call by its status code like the http status codes of the json endpoints.
+`NOT_FOUND` and `UNIMPLEMENTED` point at a wrong endpoint or service rather than a bad vote, like
+`404` and `405`, so they are retried until the endpoint is fixed
+*/
+fn classify_status(status: Status) -> ForwardResult {
+    let reason = format!("Endpoint responded with {:?}: {}", status.code(), status.message());
+    return match status.code() {
+        Code::Ok => ForwardResult::Delivered,
+        Code::AlreadyExists => {
+            debug!("Endpoint already knows the vote: {}", status.message());
+            ForwardResult::Delivered
+        }
+        Code::Unauthenticated | Code::PermissionDenied => ForwardResult::Unauthorized { reason },
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted | Code::Internal
+        | Code::Unknown | Code::Cancelled | Code::DataLoss | Code::NotFound | Code::Unimplemented => {
+            ForwardResult::Retryable { reason, retry_after: None }
+        }
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => ForwardResult::Rejected { reason },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::forward_outcome;
+
+    #[test]
+    fn classifies_every_status_code() {
+        let expected = [
+            (Code::Ok, "delivered"),
+            (Code::Cancelled, "retryable"),
+            (Code::Unknown, "retryable"),
+            (Code::InvalidArgument, "rejected"),
+            (Code::DeadlineExceeded, "retryable"),
+            (Code::NotFound, "retryable"),
+            (Code::AlreadyExists, "delivered"),
+            (Code::PermissionDenied, "unauthorized"),
+            (Code::ResourceExhausted, "retryable"),
+            (Code::FailedPrecondition, "rejected"),
+            (Code::Aborted, "retryable"),
+            (Code::OutOfRange, "rejected"),
+            (Code::Unimplemented, "retryable"),
+            (Code::Internal, "retryable"),
+            (Code::Unavailable, "retryable"),
+            (Code::DataLoss, "retryable"),
+            (Code::Unauthenticated, "unauthorized"),
+        ];
+        for (code, outcome) in expected.iter() {
+            let result = classify_status(Status::new(*code, "failed"));
+            assert_eq!(forward_outcome(&result), *outcome, "{:?}", code);
+        }
+        // every code from 0 to 16 is covered
+        let codes: Vec<i32> = expected.iter().map(|(code, _)| *code as i32).collect();
+        assert_eq!(codes, (0..=16).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,164 @@
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use warp::http::StatusCode;
+use crate::config;
+use crate::constants::SINK_KIND_GRPC;
+use crate::grpc_sink;
+use crate::vote_cache::now_millis;
+use crate::vote_handler::QueueProbe;
+
+const STATUS_OK: &str = "ok";
+const STATUS_FAIL: &str = "fail";
+
+/**
+How often the processing loop records a heartbeat while it has nothing to do
+*/
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+
+static LAST_HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+/**
+Records that the processing loop is running, called on every iteration of it
+*/
+pub fn heartbeat() {
+    LAST_HEARTBEAT.store(now_millis(), Ordering::Relaxed);
+}
+
+/**
+GET /healthz for liveness and GET /readyz for readiness, both respond with 503 if any check failed
+*/
+pub fn routes(probe: QueueProbe) -> BoxedFilter<(Box<dyn Reply>,)> {
+    let rest_probe = probe.clone();
+    let healthz = warp::get()
+        .and(warp::path!("healthz"))
+        .and(warp::any().map(move || { rest_probe.clone() }))
+        .then(|probe: QueueProbe| async move {
+            let checks = json!({
+                "processingLoop": check_heartbeat(),
+                "queues": check_queues(&probe),
+            });
+            return respond(checks);
+        });
+    let rest_probe = probe.clone();
+    let readyz = warp::get()
+        .and(warp::path!("readyz"))
+        .and(warp::any().map(move || { rest_probe.clone() }))
+        .then(|probe: QueueProbe| async move {
+            let mut checks = json!({ "storage": check_storage(&probe).await });
+            if config::get().ready_check_endpoint {
+                checks["endpoint"] = check_endpoint().await;
+            }
+            return respond(checks);
+        });
+
+    return healthz
+        .or(readyz).unify()
+        .boxed();
+}
+
+/**
+Checks the caches of all sinks are usable. They are probed directly rather than through the tasks of
+the sinks, so a sink which is busy delivering doesn't fail readiness
+*/
+async fn check_storage(probe: &QueueProbe) -> Value {
+    let backend = config::get().cache_backend.clone();
+    return match probe.check_storage(config::get().health_timeout()).await {
+        Ok(()) => json!({ "status": STATUS_OK, "backend": backend }),
+        Err(err) => json!({ "status": STATUS_FAIL, "backend": backend, "error": err }),
+    };
+}
+
+/**
+Every sink queue has a task of its own, which is dead if it no longer takes votes
+*/
+fn check_queues(probe: &QueueProbe) -> Value {
+    let stopped = probe.stopped();
+    if !stopped.is_empty() {
+        return json!({ "status": STATUS_FAIL, "error": "Sink queues stopped", "stopped": stopped });
+    }
+    return json!({ "status": STATUS_OK });
+}
+
+/**
+The processing loop is alive if its last heartbeat is younger than health_timeout. It is checked by
+age rather than passing a task through the loop, so a busy loop doesn't count as dead
+*/
+fn check_heartbeat() -> Value {
+    let last_heartbeat = LAST_HEARTBEAT.load(Ordering::Relaxed);
+    if last_heartbeat == 0 {
+        return json!({ "status": STATUS_FAIL, "error": "Processing loop not started" });
+    }
+    let age_ms = now_millis().saturating_sub(last_heartbeat);
+    if age_ms > config::get().health_timeout().as_millis() as u64 {
+        return json!({ "status": STATUS_FAIL, "error": "Processing loop stalled", "heartbeatAgeMs": age_ms });
+    }
+    return json!({ "status": STATUS_OK, "heartbeatAgeMs": age_ms });
+}
+
+/**
+The vote endpoint is considered reachable if it answers with any http status, a grpc endpoint if it
+accepts a connection
+*/
+async fn check_endpoint() -> Value {
+    let config = config::get();
+    if config.endpoint_protocol == SINK_KIND_GRPC {
+        return match grpc_sink::check_reachable(config.endpoint.as_str(), config.health_timeout()).await {
+            Ok(()) => json!({ "status": STATUS_OK }),
+            Err(err) => json!({ "status": STATUS_FAIL, "error": err }),
+        };
+    }
+    let client = reqwest::Client::builder()
+        .timeout(config.health_timeout())
+        .build()
+        .unwrap();
+    return match client.head(config.endpoint.as_str()).send().await {
+        Ok(response) => json!({ "status": STATUS_OK, "httpStatus": response.status().as_u16() }),
+        Err(err) => json!({ "status": STATUS_FAIL, "error": err.to_string() }),
+    };
+}
+
+fn respond(checks: Value) -> Box<dyn Reply> {
+    let healthy = checks.as_object()
+        .map(|checks| checks.values().all(|check| check["status"] == STATUS_OK))
+        .unwrap_or(false);
+    let (status, code) = if healthy {
+        (STATUS_OK, StatusCode::OK)
+    } else {
+        (STATUS_FAIL, StatusCode::SERVICE_UNAVAILABLE)
+    };
+    return Box::new(warp::reply::with_status(
+        warp::reply::json(&json!({ "status": status, "checks": checks })), code));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeat_keeps_processing_loop_alive() {
+        LAST_HEARTBEAT.store(0, Ordering::Relaxed);
+        assert_eq!(check_heartbeat()["status"], STATUS_FAIL);
+
+        heartbeat();
+        assert_eq!(check_heartbeat()["status"], STATUS_OK);
+
+        let stalled = now_millis() - config::get().health_timeout().as_millis() as u64 - 1000;
+        LAST_HEARTBEAT.store(stalled, Ordering::Relaxed);
+        let check = check_heartbeat();
+        assert_eq!(check["status"], STATUS_FAIL);
+        assert_eq!(check["error"], "Processing loop stalled");
+    }
+
+    #[test]
+    fn any_failed_check_is_unavailable() {
+        let ok = json!({ "status": STATUS_OK });
+        let fail = json!({ "status": STATUS_FAIL });
+        let response = respond(json!({ "processingLoop": ok, "queues": ok })).into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = respond(json!({ "processingLoop": ok, "queues": fail })).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,151 @@
+use aes_gcm::aead::Aead;
+use aes_gcm::{Aes256Gcm, Nonce};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::{Digest, Sha256, Sha512};
+use crate::auth_keys::AuthKey;
+
+pub const HEADER_SIGNATURE: &str = "x-webhook-signature";
+pub const HEADER_NONCE: &str = "x-webhook-nonce";
+pub const HEADER_PROTOCOL: &str = "x-webhook-protocol";
+pub const PROTOCOL_V2: &str = "splashtail";
+
+const IV_LENGTH: usize = 12;
+
+#[derive(Deserialize)]
+struct EncryptedBody {
+    data: String,
+}
+
+/**
+Verifies the signature of a v2 webhook body with the key and decrypts its payload.
+
+The signature is the hex HMAC-SHA512, keyed with the nonce, of the hex HMAC-SHA512 of the body
+keyed with the secret. The body is `{"data": "<hex>"}`, the AES-256-GCM encrypted payload prefixed
+with its 12 byte IV, using the SHA-256 of secret and nonce as key.
+*/
+pub fn open(key: &AuthKey, nonce: &str, signature: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body_mac = <Hmac<Sha512> as KeyInit>::new_from_slice(key.secret_bytes()).unwrap();
+    body_mac.update(body);
+    let body_hash = hex::encode(body_mac.finalize().into_bytes());
+    let mut signature_mac = <Hmac<Sha512> as KeyInit>::new_from_slice(nonce.as_bytes()).unwrap();
+    signature_mac.update(body_hash.as_bytes());
+    let signature = hex::decode(signature).map_err(|_| "signature is not hex encoded".to_owned())?;
+    signature_mac.verify_slice(signature.as_slice()).map_err(|_| "signature mismatch".to_owned())?;
+
+    let body: EncryptedBody = serde_json::from_slice(body).map_err(|err| format!("unparsable body: {}", err))?;
+    let data = hex::decode(body.data).map_err(|_| "data is not hex encoded".to_owned())?;
+    if data.len() <= IV_LENGTH {
+        return Err("data is too short".to_owned());
+    }
+    let (iv, ciphertext) = data.split_at(IV_LENGTH);
+    let mut hasher = Sha256::new();
+    hasher.update(key.secret_bytes());
+    hasher.update(nonce.as_bytes());
+    let cipher = Aes256Gcm::new_from_slice(hasher.finalize().as_slice()).unwrap();
+    return cipher.decrypt(Nonce::from_slice(iv), ciphertext)
+        .map_err(|_| "data could not be decrypted".to_owned());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth_keys::AuthKeys;
+
+    /**
+    A webhook signed and encrypted by an independent implementation of the scheme
+    */
+    #[derive(Deserialize)]
+    struct Fixture {
+        secret: String,
+        nonce: String,
+        signature: String,
+        body: String,
+        payload: String,
+    }
+
+    fn fixture() -> Fixture {
+        return serde_json::from_str(include_str!("../tests/fixtures/ibl_webhook.json")).unwrap();
+    }
+
+    fn sign(secret: &str, nonce: &str, body: &[u8]) -> String {
+        let mut body_mac = <Hmac<Sha512> as KeyInit>::new_from_slice(secret.as_bytes()).unwrap();
+        body_mac.update(body);
+        let mut signature_mac = <Hmac<Sha512> as KeyInit>::new_from_slice(nonce.as_bytes()).unwrap();
+        signature_mac.update(hex::encode(body_mac.finalize().into_bytes()).as_bytes());
+        return hex::encode(signature_mac.finalize().into_bytes());
+    }
+
+    #[test]
+    fn opens_signed_payload() {
+        let fixture = fixture();
+        let keys = AuthKeys::single(fixture.secret.as_str());
+        let key = keys.active(0).next().unwrap();
+
+        let payload = open(key, fixture.nonce.as_str(), fixture.signature.as_str(), fixture.body.as_bytes());
+        assert_eq!(payload.unwrap(), fixture.payload.into_bytes());
+    }
+
+    #[test]
+    fn rejects_other_secret() {
+        let fixture = fixture();
+        let keys = AuthKeys::single("another-secret");
+        let key = keys.active(0).next().unwrap();
+
+        let result = open(key, fixture.nonce.as_str(), fixture.signature.as_str(), fixture.body.as_bytes());
+        assert_eq!(result.unwrap_err(), "signature mismatch");
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let fixture = fixture();
+        let keys = AuthKeys::single(fixture.secret.as_str());
+        let key = keys.active(0).next().unwrap();
+        let mut signature = fixture.signature.into_bytes();
+        signature[0] = if signature[0] == b'0' { b'1' } else { b'0' };
+
+        let result = open(key, fixture.nonce.as_str(), std::str::from_utf8(&signature).unwrap(), fixture.body.as_bytes());
+        assert_eq!(result.unwrap_err(), "signature mismatch");
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let fixture = fixture();
+        let keys = AuthKeys::single(fixture.secret.as_str());
+        let key = keys.active(0).next().unwrap();
+        let body = fixture.body.replacen("0001", "0101", 1);
+
+        let result = open(key, fixture.nonce.as_str(), fixture.signature.as_str(), body.as_bytes());
+        assert_eq!(result.unwrap_err(), "signature mismatch");
+    }
+
+    #[test]
+    fn rejects_tampered_ciphertext() {
+        let fixture = fixture();
+        let keys = AuthKeys::single(fixture.secret.as_str());
+        let key = keys.active(0).next().unwrap();
+        let body: EncryptedBody = serde_json::from_str(fixture.body.as_str()).unwrap();
+        let mut data = hex::decode(body.data).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        // signed again, so only the authentication of the ciphertext can catch it
+        let body = format!(r#"{{"data": "{}"}}"#, hex::encode(data));
+        let signature = sign(fixture.secret.as_str(), fixture.nonce.as_str(), body.as_bytes());
+
+        let result = open(key, fixture.nonce.as_str(), signature.as_str(), body.as_bytes());
+        assert_eq!(result.unwrap_err(), "data could not be decrypted");
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let fixture = fixture();
+        let keys = AuthKeys::single(fixture.secret.as_str());
+        let key = keys.active(0).next().unwrap();
+        let body = r#"{"data": "000102030405060708090a0b"}"#;
+        let signature = sign(fixture.secret.as_str(), fixture.nonce.as_str(), body.as_bytes());
+
+        let result = open(key, fixture.nonce.as_str(), signature.as_str(), body.as_bytes());
+        assert_eq!(result.unwrap_err(), "data is too short");
+    }
+}
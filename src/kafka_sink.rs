@@ -0,0 +1,145 @@
+use async_trait::async_trait;
+use log::{error, info, warn};
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::{Header, OwnedHeaders};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::util::Timeout;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use crate::config::{self, SinkConfig};
+use crate::forward_result::ForwardResult;
+use crate::vote_request::{VoteEnvelope, VoteRequest};
+use crate::vote_sink::VoteSink;
+
+const HEADER_IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/**
+A producer with the settings it was created from
+*/
+struct KafkaProducer {
+    settings: BTreeMap<String, String>,
+    producer: FutureProducer,
+}
+
+/**
+Produces votes wrapped in a `VoteEnvelope` to the topic of the sink, keyed by the id of the bot or guild so the
+votes of a bot stay in order on one partition. A vote counts as delivered once the brokers acknowledged it.
+*/
+pub struct KafkaSink {
+    route: String,
+    name: String,
+    producer: Mutex<Option<KafkaProducer>>,
+}
+
+impl KafkaSink {
+    pub fn new(route: &str, name: &str) -> KafkaSink {
+        return KafkaSink {
+            route: route.to_owned(),
+            name: name.to_owned(),
+            producer: Mutex::new(None),
+        };
+    }
+
+    /**
+    The producer for the settings of the sink, created anew when they changed
+    */
+    fn producer(&self, sink: &SinkConfig, brokers: &str) -> Result<FutureProducer, KafkaError> {
+        let mut settings = BTreeMap::new();
+        settings.insert("bootstrap.servers".to_owned(), brokers.to_owned());
+        settings.insert("message.timeout.ms".to_owned(), config::get().sink_timeout().as_millis().to_string());
+        settings.insert("enable.idempotence".to_owned(), "true".to_owned());
+        if let Some(options) = sink.options.as_ref() {
+            settings.extend(options.clone());
+        }
+        let mut current = self.producer.lock().unwrap();
+        if let Some(open) = current.as_ref() {
+            if open.settings == settings {
+                return Ok(open.producer.clone());
+            }
+        }
+        let mut client_config = ClientConfig::new();
+        settings.iter().for_each(|(key, value)| { client_config.set(key, value); });
+        let producer: FutureProducer = client_config.create()?;
+        info!("Created kafka producer for sink {} of route {}", self.name, self.route);
+        *current = Some(KafkaProducer { settings, producer: producer.clone() });
+        return Ok(producer);
+    }
+}
+
+#[async_trait]
+impl VoteSink for KafkaSink {
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+        let config = config::get();
+        let sink = config.sink(self.route.as_str(), self.name.as_str());
+        let target = sink.and_then(|sink| Some((sink, sink.endpoint.as_deref()?, sink.topic.as_deref()?)));
+        let (sink, brokers, topic) = match target {
+            Some(target) => target,
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} is not configured", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let producer = match self.producer(sink, brokers) {
+            Ok(producer) => producer,
+            Err(err) => {
+                error!("Creating kafka producer for sink {} of route {} failed: {}", self.name, self.route, err);
+                return ForwardResult::Retryable { reason: format!("Creating producer failed: {}", err), retry_after: None };
+            }
+        };
+        let payload = serde_json::to_vec(&VoteEnvelope::new(vote, self.route.as_str())).unwrap();
+        let key = record_key(vote);
+        let mut record = FutureRecord::to(topic)
+            .key(key.as_str())
+            .payload(payload.as_slice());
+        if let Some(idempotency_key) = vote.idempotency_key.as_ref() {
+            record = record.headers(OwnedHeaders::new().insert(Header {
+                key: HEADER_IDEMPOTENCY_KEY,
+                value: Some(idempotency_key.as_str()),
+            }));
+        }
+        return match producer.send(record, Timeout::After(config.sink_timeout())).await {
+            Ok(_) => ForwardResult::Delivered,
+            Err((err, _)) => {
+                warn!("Producing to kafka sink {} of route {} failed: {}", self.name, self.route, err);
+                classify_error(err)
+            }
+        };
+    }
+}
+
+/**
+The id of the bot or guild voted for, so all votes of one land in the same partition in order
+*/
+fn record_key(vote: &VoteRequest) -> String {
+    return vote.target().0.to_string();
+}
+
+fn classify_error(err: KafkaError) -> ForwardResult {
+    let reason = format!("Produce failed: {}", err);
+    return match err.rdkafka_error_code() {
+        Some(RDKafkaErrorCode::TopicAuthorizationFailed)
+        | Some(RDKafkaErrorCode::ClusterAuthorizationFailed)
+        | Some(RDKafkaErrorCode::SaslAuthenticationFailed) => ForwardResult::Unauthorized { reason },
+        Some(RDKafkaErrorCode::MessageSizeTooLarge) => ForwardResult::Rejected { reason },
+        _ => ForwardResult::Retryable { reason, retry_after: None },
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_keyed_by_bot_or_guild() {
+        let bot_vote: VoteRequest = serde_json::from_str(
+            r#"{"bot":"42","user":"1","type":"vote","isWeekend":false,"query":null,"src":"topgg"}"#).unwrap();
+        let guild_vote: VoteRequest = serde_json::from_str(
+            r#"{"bot":"0","guild":"9","kind":"guild","user":"1","type":"vote","isWeekend":false,"query":null,"src":"topgg"}"#)
+            .unwrap();
+        assert_eq!(record_key(&bot_vote), "42");
+        assert_eq!(record_key(&guild_vote), "9");
+    }
+}
@@ -1,27 +1,80 @@
-use std::ops::Deref;
-use crate::vote_handler::VoteHandler;
+#![allow(clippy::needless_return)]
+
+use crate::vote_handler::{VoteHandler, SinkQueue};
 use warp::Filter;
-use crate::vote_request::{VoteRequest, Vote, TopVoteRequest, DblComVoteRequest, BfdVoteRequest, DBoatsVoteRequest, DBoatsBotData, DiscordListVoteRequest};
+use crate::vote_request::{VoteRequest, Vote, TopVoteRequest, DblComVoteRequest, DblComV2VoteRequest, BfdVoteRequest, DiscordsVoteRequest, DBoatsVoteRequest, DBoatsBotData, IblVoteRequest, VoidBotsVoteRequest, DiscordLabsVoteRequest, BotListMeVoteRequest, DiscordListVoteRequest};
 use crate::cache_task::CacheTask;
-use crate::constants::{CACHE_TASK_OP_VOTE, CACHE_TASK_OP_RESEND, PAGE_KEY_TOPGG, PAGE_KEY_DBL, PAGE_KEY_BFD, PAGE_KEY_DBOATS, PAGE_KEY_DLIST};
+use crate::constants::{PAGE_KEY_GENERIC, PAGE_KEY_IBL, PAGE_KEY_DLIST};
 use warp::http::StatusCode;
 use tokio::sync::mpsc::Sender;
-use log::{info, debug, warn};
+use log::{info, debug, warn, error};
 use warp::hyper::body::Bytes;
 use crate::snowflake::Snowflake;
+use std::sync::Arc;
+use crate::vote_dedup::{DedupStore, Forwarded};
+use crate::config::Config;
+use crate::auth_keys::AuthKeys;
+use crate::auth_failure::{AuthFailure, ClientInfo};
 
 mod snowflake;
 mod vote_request;
 mod constants;
+mod config;
+mod auth_keys;
+mod auth_failure;
 mod vote_cache;
+mod wal_vote_cache;
+mod sqlite_vote_cache;
+mod redis_vote_cache;
 mod vote_handler;
+mod vote_sink;
+mod discord_sink;
+mod amqp_sink;
+mod kafka_sink;
+mod nats_sink;
+mod grpc_sink;
+mod ibl_webhook;
+mod retry_policy;
+mod vote_dedup;
+mod forward_result;
 mod cache_task;
+mod admin;
+mod metrics;
+mod health;
 
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    info!("Starting vote-handler using proxy url {}", constants::VOTE_ENDPOINT.clone().as_str());
+    let config_path = config::config_path();
+    let config = match Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(errors) => {
+            errors.iter().for_each(|err| error!("Invalid config: {}", err));
+            std::process::exit(1);
+        }
+    };
+    if std::env::args().any(|arg| arg == "--check-config") {
+        print!("{}", config.to_redacted_toml());
+        return;
+    }
+    config::install(config);
+    tokio::spawn(config::watch(config_path));
+    metrics::init();
+    info!("Starting vote-handler using proxy url {}", config::get().endpoint);
     let (tx, mut rx) = tokio::sync::mpsc::channel(128);
+    let http_client = reqwest::Client::builder()
+        .http1_title_case_headers()
+        .build()
+        .unwrap();
+    let mut queues = Vec::new();
+    for (route, sink, kind) in config::get().queue_names() {
+        let vote_sink = vote_sink::create_vote_sink(&http_client, route.as_str(), sink.as_str(), kind.as_str());
+        let cache = vote_cache::create_vote_cache(route.as_str(), sink.as_str()).await;
+        queues.push(SinkQueue::new(route, sink, vote_sink, cache));
+    }
+    let vote_handler = VoteHandler::new(queues);
+    let probe = vote_handler.probe();
+    let dedup = vote_dedup::create_dedup_store().await;
 
     let scheduler_tx = tx.clone();
     tokio::spawn(async move {
@@ -32,147 +85,513 @@ async fn main() {
             if result.is_err() {
                 warn!("Failed to start resend task")
             }
-            tokio::time::sleep(*constants::VOTE_RESEND_DELAY).await;
+            tokio::time::sleep(config::get().resend_delay()).await;
         }
     });
 
     tokio::spawn(async move {
         info!("Started processing loop");
-        let mut vote_handler = VoteHandler::new();
+        let mut heartbeat = tokio::time::interval(health::HEARTBEAT_INTERVAL);
         loop {
-            debug!("Awaiting next inbound");
-            let rec = rx.recv().await;
-            if rec.is_some() {
-                let task = rec.unwrap();
-                if task.op == CACHE_TASK_OP_VOTE {
-                    vote_handler
-                        .accept_vote_request(task.vote.unwrap()).await;
-                } else if task.op == CACHE_TASK_OP_RESEND {
-                    vote_handler.resend_votes().await;
-                }
+            health::heartbeat();
+            let rec = tokio::select! {
+                rec = rx.recv() => rec,
+                _ = heartbeat.tick() => continue,
+            };
+            if let Some(task) = rec {
+                vote_handler.process(task);
             }
         }
     });
 
     let options = warp::options().map(|| { Ok(Box::new("OPTIONS")) });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let generic_vote = warp::path!("vote" / "generic")
-        .and(warp::header("authorization"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
         .and(warp::body::json())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|authorization: String, body: VoteRequest, tx: Sender<CacheTask>| async move {
-            return process_vote_request(tx, authorization, body, true).await;
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: VoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_vote_request(tx, dedup, client, authorization, body, true).await;
         });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let top_vote = warp::path!("vote" / "topgg")
-        .and(warp::header("authorization"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
         .and(warp::body::json())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|authorization: String, body: TopVoteRequest, tx: Sender<CacheTask>| async move {
-            return process_vote_request(tx, authorization, body, false).await;
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: TopVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            if !body.has_single_target() {
+                warn!("Top.gg vote of user {} names neither or both a bot and a guild", body.user.0);
+                let res: Result<Box<dyn warp::Reply>, warp::Rejection> = Ok(Box::new(StatusCode::BAD_REQUEST));
+                return res;
+            }
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
         });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let bfd_vote = warp::path!("vote" / "bfd")
-        .and(warp::header("authorization"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
+        .and(warp::body::json())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: BfdVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
+        });
+    let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
+    let bfd_v2_vote = warp::path!("vote" / "bfd" / "v2")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
         .and(warp::body::json())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|authorization: String, body: BfdVoteRequest, tx: Sender<CacheTask>| async move {
-            return process_vote_request(tx, authorization, body, false).await;
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: DiscordsVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_discords_vote_request(tx, dedup, client, authorization, body).await;
         });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let dbl_vote = warp::path!("vote" / "dbl" / u64)
-        .and(warp::header("authorization"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
         .and(warp::body::json())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|param: u64, authorization: String, mut body: DblComVoteRequest, tx: Sender<CacheTask>| async move {
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|param: u64, authorization: Option<String>, client: ClientInfo, mut body: DblComVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
             body.bot = Some(Snowflake(param));
-            return process_vote_request(tx, authorization, body, false).await;
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
         });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
+    let dblcom_vote = warp::path!("vote" / "dblcom" / u64)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
+        .and(warp::body::json())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|param: u64, authorization: Option<String>, client: ClientInfo, mut body: DblComV2VoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            body.bot = Some(Snowflake(param));
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
+        });
+    let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let dboats_vote = warp::path!("vote" / "dboats")
-        .and(warp::header("authorization"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
         .and(warp::body::json())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|authorization: String, body: DBoatsVoteRequest, tx: Sender<CacheTask>| async move {
-            return process_vote_request(tx, authorization, body, false).await;
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: DBoatsVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
         });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let dlist_vote = warp::path!("vote" / "dlist")
+        .and(auth_failure::client_info())
         .and(warp::body::bytes())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|body: Bytes, tx: Sender<CacheTask>| async move {
-            use jwt::VerifyWithKey;
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|client: ClientInfo, body: Bytes, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            use jwt::{Header, Token, VerifyWithKey};
             let content = String::from_utf8(body.to_vec()).expect("Failed to get body as text");
 
-            match content.verify_with_key(constants::VOTE_AUTH_KEY_DLIST.deref()) {
-                Ok(body) => {
-                    let body: DiscordListVoteRequest = body;
-                    process_vote_request(tx, "".to_owned(), body, false).await
+            let config = config::get();
+            // the keys depend on the route of the bot, which is only known from the claims
+            let keys = match Token::<Header, DiscordListVoteRequest, _>::parse_unverified(content.as_str()) {
+                Ok(token) => config.auth_keys(token.claims().bot_id.0, Some(PAGE_KEY_DLIST)),
+                Err(_) => config.auth_token_dlist(),
+            };
+            let now = vote_cache::now_millis() / 1000;
+            let mut result = Err("no active key".to_owned());
+            for key in keys.active(now) {
+                result = content.verify_with_key(&key.hmac())
+                    .map(|body: DiscordListVoteRequest| (key, body))
+                    .map_err(|err| err.to_string());
+                if result.is_ok() {
+                    break;
                 }
-                Err(err) => {
-                    warn!("Failed to verify dlist request: {}", err);
+            }
+            match result {
+                Ok((key, body)) => {
+                    debug!("Verified dlist request with key {}", key.id);
+                    metrics::count_auth_key(PAGE_KEY_DLIST, key.id.as_str());
+                    process_vote_request(tx, dedup, client, None, body, false).await
+                }
+                Err(error) => {
+                    auth_failure::report(PAGE_KEY_DLIST, &AuthFailure::BadJwt { error }, &client);
                     let res: Result<Box<dyn warp::Reply>, warp::Rejection> = Ok(Box::new(StatusCode::UNAUTHORIZED));
                     res
                 }
             }
         });
     let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
+    let ibl_vote = warp::path!("vote" / "ibl")
+        .and(warp::header::optional::<String>(ibl_webhook::HEADER_PROTOCOL))
+        .and(warp::header::optional::<String>(ibl_webhook::HEADER_SIGNATURE))
+        .and(warp::header::optional::<String>(ibl_webhook::HEADER_NONCE))
+        .and(auth_failure::client_info())
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|protocol: Option<String>, signature: Option<String>, nonce: Option<String>, client: ClientInfo, body: Bytes, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            let res: Result<Box<dyn warp::Reply>, warp::Rejection>;
+            if protocol.as_deref() != Some(ibl_webhook::PROTOCOL_V2) {
+                warn!("Rejecting ibl webhook with protocol {:?}", protocol);
+                res = Ok(Box::new(StatusCode::BAD_REQUEST));
+                return res;
+            }
+            let (signature, nonce) = match (signature, nonce) {
+                (Some(signature), Some(nonce)) => (signature, nonce),
+                _ => {
+                    let error = "missing signature or nonce header".to_owned();
+                    auth_failure::report(PAGE_KEY_IBL, &AuthFailure::BadSignature { error }, &client);
+                    res = Ok(Box::new(StatusCode::UNAUTHORIZED));
+                    return res;
+                }
+            };
+
+            let config = config::get();
+            // the payload is encrypted, so the route of the bot is only known after trying every key
+            let now = vote_cache::now_millis() / 1000;
+            let mut result = Err("no active key".to_owned());
+            for key in config.source_auth_keys(PAGE_KEY_IBL).into_iter().flat_map(|keys| keys.active(now)) {
+                result = ibl_webhook::open(key, nonce.as_str(), signature.as_str(), &body)
+                    .map(|payload| (key, payload));
+                if result.is_ok() {
+                    break;
+                }
+            }
+            let (key, payload) = match result {
+                Ok(opened) => opened,
+                Err(error) => {
+                    auth_failure::report(PAGE_KEY_IBL, &AuthFailure::BadSignature { error }, &client);
+                    res = Ok(Box::new(StatusCode::UNAUTHORIZED));
+                    return res;
+                }
+            };
+            let body = match serde_json::from_slice::<IblVoteRequest>(payload.as_slice()) {
+                Ok(body) => body,
+                Err(err) => {
+                    warn!("Unparsable ibl payload: {}", err);
+                    res = Ok(Box::new(StatusCode::BAD_REQUEST));
+                    return res;
+                }
+            };
+            if !body.is_vote() {
+                debug!("Ignoring ibl event {}", body.r#type);
+                res = Ok(Box::new(StatusCode::NO_CONTENT));
+                return res;
+            }
+            if !config.auth_keys(body.get_bot().0, Some(PAGE_KEY_IBL)).active(now).any(|active| std::ptr::eq(active, key)) {
+                auth_failure::report(PAGE_KEY_IBL, &AuthFailure::WrongToken, &client);
+                res = Ok(Box::new(StatusCode::UNAUTHORIZED));
+                return res;
+            }
+            debug!("Verified ibl request with key {}", key.id);
+            metrics::count_auth_key(PAGE_KEY_IBL, key.id.as_str());
+            process_vote_request(tx, dedup, client, None, body, false).await
+        });
+    let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
+    let voidbots_vote = warp::path!("vote" / "voidbots")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
+        .and(warp::body::json())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: VoidBotsVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
+        });
+    let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
+    let dlabs_vote = warp::path!("vote" / "dlabs")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
+        .and(warp::body::json())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: DiscordLabsVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_dlabs_vote_request(tx, dedup, client, authorization, body).await;
+        });
+    let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
+    let botlistme_vote = warp::path!("vote" / "botlistme")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
+        .and(warp::body::json())
+        .and(warp::any().map(move || { rest_tx.clone() }))
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|authorization: Option<String>, client: ClientInfo, body: BotListMeVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
+        });
+    let rest_tx = tx.clone();
+    let rest_dedup = dedup.clone();
     let dboats_vote_old = warp::path!("vote" / "dboats" / u64)
-        .and(warp::header("authorization"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(auth_failure::client_info())
         .and(warp::body::json())
         .and(warp::any().map(move || { rest_tx.clone() }))
-        .and_then(|param: u64, authorization: String, mut body: DBoatsVoteRequest, tx: Sender<CacheTask>| async move {
+        .and(warp::any().map(move || { rest_dedup.clone() }))
+        .and_then(|param: u64, authorization: Option<String>, client: ClientInfo, mut body: DBoatsVoteRequest, tx: Sender<CacheTask>, dedup: Arc<dyn DedupStore>| async move {
             body.bot = Some(DBoatsBotData {
                 id: Snowflake(param),
                 name: "Bot".to_owned()
             });
-            return process_vote_request(tx, authorization, body, false).await;
+            return process_vote_request(tx, dedup, client, authorization, body, false).await;
         });
 
     info!("Starting rest server");
     warp::serve(options.or(warp::post().and(generic_vote.or(top_vote)
-        .or(bfd_vote).or(dbl_vote).or(dlist_vote).or(dboats_vote).or(dboats_vote_old))))
+        .or(bfd_vote).or(bfd_v2_vote).or(dbl_vote).or(dblcom_vote).or(ibl_vote).or(voidbots_vote)
+        .or(dlabs_vote).or(botlistme_vote).or(dlist_vote).or(dboats_vote).or(dboats_vote_old)))
+        .or(admin::routes(tx.clone()))
+        .or(metrics::route())
+        .or(health::routes(probe)))
         .run(([0, 0, 0, 0], 8080))
         .await;
 }
 
-async fn process_vote_request<V: Vote>(sender: Sender<CacheTask>, auth: String, generic_vote: V,
-                                       generic: bool)
+async fn process_vote_request<V: Vote>(sender: Sender<CacheTask>, dedup: Arc<dyn DedupStore>, client: ClientInfo,
+                                       auth: Option<String>, generic_vote: V, generic: bool)
                                        -> Result<Box<dyn warp::Reply>, warp::Rejection> {
-    let vote = map_request(generic_vote);
-    let expected_auth;
-    if generic {
-        expected_auth = Some(constants::VOTE_AUTH_TOKEN.clone());
+    let mut vote = map_request(generic_vote);
+    // the src of generic requests is chosen by the client, don't let it create metric series
+    let source = if generic { PAGE_KEY_GENERIC.to_owned() } else { vote.get_source() };
+    let config = config::get();
+    let expected_auth = if generic {
+        Some(config.auth_keys(vote.target().0, None))
     } else {
-        expected_auth = get_auth(vote.clone());
+        get_auth(&config, &vote)
+    };
+    let matched = match (expected_auth, auth) {
+        (Some(keys), Some(auth)) => keys.verify(auth.as_str(), vote_cache::now_millis() / 1000).map(Some),
+        (Some(_), None) => Err(AuthFailure::MissingHeader),
+        (None, _) => Ok(None),
+    };
+    let matched = match matched {
+        Ok(matched) => matched,
+        Err(failure) => {
+            auth_failure::report(source.as_str(), &failure, &client);
+            return Ok(Box::new(StatusCode::UNAUTHORIZED));
+        }
+    };
+    if let Some(key) = matched {
+        debug!("Authorized {} request with key {}", vote.get_source(), key.id);
+        metrics::count_auth_key(source.as_str(), key.id.as_str());
     }
-    return if expected_auth.is_none() || auth.eq(expected_auth.unwrap().as_str()) {
-        let result = sender.send(CacheTask::create_vote_task(auth, vote)).await;
-        if result.is_ok() {
+    let received_at = vote_cache::now_millis();
+    let dedup_key = vote_dedup::dedup_key(&vote);
+    let key = vote.idempotency_key.clone()
+        .unwrap_or_else(|| vote_dedup::idempotency_key(&vote, received_at));
+    let user = vote.user.0;
+    vote.idempotency_key = Some(key.clone());
+    vote.received_at = Some(received_at);
+    let forward = || async move { sender.send(CacheTask::create_vote_task(vote)).await.is_ok() };
+    return match vote_dedup::forward_once(dedup.as_ref(), dedup_key.as_str(), forward).await {
+        Forwarded::Accepted => {
+            metrics::VOTES_RECEIVED.with_label_values(&[source.as_str(), metrics::RECEIVED_ACCEPTED]).inc();
             Ok(Box::new(r#"{"status":"OK"}"#))
-        } else {
-            Err(warp::reject::not_found())
         }
-    } else {
-        warn!("Dropping unauthorized request!");
-        Ok(Box::new(StatusCode::UNAUTHORIZED))
+        Forwarded::Duplicate => {
+            info!("Dropping duplicate vote {} from {} via {}", key, user, source);
+            metrics::VOTES_RECEIVED.with_label_values(&[source.as_str(), metrics::RECEIVED_DUPLICATE]).inc();
+            Ok(Box::new(r#"{"status":"OK"}"#))
+        }
+        Forwarded::Failed => Err(warp::reject::not_found()),
     };
 }
 
+/**
+A discords.com v2 webhook names either a bot or a guild, requests naming neither or both are refused
+*/
+async fn process_discords_vote_request(sender: Sender<CacheTask>, dedup: Arc<dyn DedupStore>, client: ClientInfo,
+                                       auth: Option<String>, vote: DiscordsVoteRequest)
+                                       -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    if !vote.has_single_target() {
+        warn!("Discords vote of user {} names neither or both a bot and a guild", vote.user.0);
+        return Ok(Box::new(StatusCode::BAD_REQUEST));
+    }
+    return process_vote_request(sender, dedup, client, auth, vote, false).await;
+}
+
+/**
+Discord Labs sends its secret in the `token` field of the body, which is checked if no Authorization
+header was sent
+*/
+async fn process_dlabs_vote_request(sender: Sender<CacheTask>, dedup: Arc<dyn DedupStore>, client: ClientInfo,
+                                    auth: Option<String>, mut vote: DiscordLabsVoteRequest)
+                                    -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let auth = auth.or_else(|| vote.token.take());
+    return process_vote_request(sender, dedup, client, auth, vote, false).await;
+}
+
 fn map_request<V: Vote>(vote: V) -> VoteRequest {
     return vote.get_as_generic();
 }
 
-pub fn get_auth(vote: VoteRequest) -> Option<String> {
-    if vote.src.is_none() {
-        return Some(constants::VOTE_AUTH_TOKEN.clone());
-    }
-    return match vote.src.unwrap().as_str() {
-        PAGE_KEY_TOPGG => Some(constants::VOTE_AUTH_TOKEN_TOPGG.clone()),
-        PAGE_KEY_DBL => Some(constants::VOTE_AUTH_TOKEN_DBL.clone()),
-        PAGE_KEY_BFD => Some(constants::VOTE_AUTH_TOKEN_BFD.clone()),
-        PAGE_KEY_DBOATS => Some(constants::VOTE_AUTH_TOKEN_DBOATS.clone()),
-        PAGE_KEY_DLIST => None,
-        _ => Some(constants::VOTE_AUTH_TOKEN.clone()),
+/**
+The keys accepted for the source of the vote, None if the request was already verified
+*/
+pub fn get_auth<'a>(config: &'a Config, vote: &VoteRequest) -> Option<&'a AuthKeys> {
+    return match vote.src.as_deref() {
+        Some(PAGE_KEY_DLIST) | Some(PAGE_KEY_IBL) => None,
+        src => Some(config.auth_keys(vote.target().0, src)),
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::Receiver;
+    use warp::Reply;
+    use crate::constants::{PAGE_KEY_BFD, PAGE_KEY_BOTLISTME, PAGE_KEY_DLABS, PAGE_KEY_VOIDBOTS, VOTE_KIND_BOT,
+                           VOTE_KIND_GUILD};
+    use crate::vote_dedup::MemoryDedupStore;
+
+    const AUTH_TOKEN: &str = "secret";
+
+    /**
+    A processing loop channel and a fresh dedup store to pass votes to, with the test config installed
+    */
+    fn loop_channel() -> (Sender<CacheTask>, Receiver<CacheTask>, Arc<dyn DedupStore>) {
+        config::install_test_config();
+        let (tx, rx) = tokio::sync::mpsc::channel(128);
+        return (tx, rx, Arc::new(MemoryDedupStore::new(60_000)));
+    }
+
+    fn client() -> ClientInfo {
+        return ClientInfo { remote: None, forwarded_for: None, user_agent: None };
+    }
+
+    /**
+    The status the request was answered with and the vote passed to the processing loop, if any
+    */
+    fn outcome(reply: Result<Box<dyn warp::Reply>, warp::Rejection>, rx: &mut Receiver<CacheTask>)
+               -> (StatusCode, Option<VoteRequest>) {
+        let status = reply.ok().unwrap().into_response().status();
+        return (status, rx.try_recv().ok().and_then(|task| task.vote));
+    }
+
+    async fn discords_vote(fixture: &str) -> (StatusCode, Option<VoteRequest>) {
+        let (tx, mut rx, dedup) = loop_channel();
+        let vote: DiscordsVoteRequest = serde_json::from_str(fixture).unwrap();
+        let reply = process_discords_vote_request(tx, dedup, client(), Some(AUTH_TOKEN.to_owned()), vote).await;
+        return outcome(reply, &mut rx);
+    }
+
+    #[tokio::test]
+    async fn discords_bot_vote_is_forwarded() {
+        let (status, vote) = discords_vote(include_str!("../tests/fixtures/discords_bot_vote.json")).await;
+        assert_eq!(status, StatusCode::OK);
+        let vote = vote.unwrap();
+        assert_eq!(vote.bot, Snowflake(681159155498696705));
+        assert_eq!(vote.user, Snowflake(215183283311542272));
+        assert_eq!(vote.guild, None);
+        assert_eq!(vote.kind, VOTE_KIND_BOT);
+        assert_eq!(vote.r#type, "vote");
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_BFD));
+    }
+
+    #[tokio::test]
+    async fn discords_guild_vote_is_forwarded() {
+        let (status, vote) = discords_vote(include_str!("../tests/fixtures/discords_guild_vote.json")).await;
+        assert_eq!(status, StatusCode::OK);
+        let vote = vote.unwrap();
+        assert_eq!(vote.bot, Snowflake(0));
+        assert_eq!(vote.guild, Some(Snowflake(696356935458734150)));
+        assert_eq!(vote.kind, VOTE_KIND_GUILD);
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_BFD));
+    }
+
+    #[tokio::test]
+    async fn discords_vote_without_target_is_refused() {
+        let (status, vote) = discords_vote(include_str!("../tests/fixtures/discords_vote_without_target.json")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(vote.is_none());
+    }
+
+    #[tokio::test]
+    async fn discords_vote_with_both_targets_is_refused() {
+        let (status, vote) = discords_vote(include_str!("../tests/fixtures/discords_vote_with_both_targets.json")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(vote.is_none());
+    }
+
+    async fn vote_of<V: Vote>(vote: V, auth: Option<&str>) -> (StatusCode, Option<VoteRequest>) {
+        let (tx, mut rx, dedup) = loop_channel();
+        let reply = process_vote_request(tx, dedup, client(), auth.map(str::to_owned), vote, false).await;
+        return outcome(reply, &mut rx);
+    }
+
+    async fn dlabs_vote(body: &str, auth: Option<&str>) -> (StatusCode, Option<VoteRequest>) {
+        let (tx, mut rx, dedup) = loop_channel();
+        let vote: DiscordLabsVoteRequest = serde_json::from_str(body).unwrap();
+        let reply = process_dlabs_vote_request(tx, dedup, client(), auth.map(str::to_owned), vote).await;
+        return outcome(reply, &mut rx);
+    }
+
+    #[tokio::test]
+    async fn voidbots_vote_is_forwarded() {
+        let vote: VoidBotsVoteRequest = serde_json::from_str(include_str!("../tests/fixtures/voidbots_vote.json")).unwrap();
+        let (status, vote) = vote_of(vote, Some(AUTH_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        let vote = vote.unwrap();
+        assert_eq!(vote.bot, Snowflake(681159155498696705));
+        assert_eq!(vote.user, Snowflake(215183283311542272));
+        assert_eq!(vote.r#type, "test");
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_VOIDBOTS));
+
+        let vote: VoidBotsVoteRequest = serde_json::from_str(r#"{"bot":"1","user":"2","type":"upvote"}"#).unwrap();
+        assert_eq!(vote_of(vote.clone(), Some(AUTH_TOKEN)).await.1.unwrap().r#type, "vote");
+        assert!(matches!(vote_of(vote, Some("wrong")).await, (StatusCode::UNAUTHORIZED, None)));
+    }
+
+    #[tokio::test]
+    async fn dlabs_vote_is_authorized_by_body_token() {
+        let fixture = include_str!("../tests/fixtures/dlabs_vote.json");
+        let (status, vote) = dlabs_vote(fixture, None).await;
+        assert_eq!(status, StatusCode::OK);
+        let vote = vote.unwrap();
+        assert_eq!(vote.bot, Snowflake(681159155498696705));
+        assert_eq!(vote.user, Snowflake(215183283311542272));
+        assert_eq!(vote.r#type, "vote");
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_DLABS));
+    }
+
+    #[tokio::test]
+    async fn dlabs_vote_with_wrong_or_without_token_is_refused() {
+        let wrong_token = r#"{"uid":"215183283311542272","bid":"681159155498696705","test":true,"token":"wrong"}"#;
+        assert!(matches!(dlabs_vote(wrong_token, None).await, (StatusCode::UNAUTHORIZED, None)));
+        let without_token = r#"{"uid":"215183283311542272","bid":"681159155498696705"}"#;
+        assert!(matches!(dlabs_vote(without_token, None).await, (StatusCode::UNAUTHORIZED, None)));
+        // the header takes precedence over the body
+        let fixture = include_str!("../tests/fixtures/dlabs_vote.json");
+        assert!(matches!(dlabs_vote(fixture, Some("wrong")).await, (StatusCode::UNAUTHORIZED, None)));
+        assert_eq!(dlabs_vote(wrong_token, Some(AUTH_TOKEN)).await.1.unwrap().r#type, "test");
+    }
+
+    #[tokio::test]
+    async fn botlistme_vote_accepts_both_field_names() {
+        let fixture = include_str!("../tests/fixtures/botlistme_vote.json");
+        let vote: BotListMeVoteRequest = serde_json::from_str(fixture).unwrap();
+        let (status, vote) = vote_of(vote, Some(AUTH_TOKEN)).await;
+        assert_eq!(status, StatusCode::OK);
+        let vote = vote.unwrap();
+        assert_eq!(vote.bot, Snowflake(681159155498696705));
+        assert_eq!(vote.user, Snowflake(215183283311542272));
+        assert_eq!(vote.r#type, "vote");
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_BOTLISTME));
+
+        let vote: BotListMeVoteRequest =
+            serde_json::from_str(r#"{"bot":"681159155498696705","user":"215183283311542272","type":"test"}"#).unwrap();
+        assert_eq!((vote.bot, vote.user), (Snowflake(681159155498696705), Snowflake(215183283311542272)));
+        assert_eq!(vote.get_as_generic().r#type, "test");
+        assert!(serde_json::from_str::<BotListMeVoteRequest>(r#"{"bot_id":"1"}"#).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use lazy_static::lazy_static;
+use prometheus::{Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry,
+                 TextEncoder};
+use warp::{Filter, Reply};
+use warp::filters::BoxedFilter;
+use crate::constants::AUTH_SOURCE_ADMIN;
+use crate::forward_result::ForwardResult;
+
+pub const RECEIVED_ACCEPTED: &str = "accepted";
+pub const RECEIVED_DUPLICATE: &str = "duplicate";
+pub const RECEIVED_UNAUTHORIZED: &str = "unauthorized";
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    /**
+    Votes received from the bot lists by source and whether they were accepted
+    */
+    pub static ref VOTES_RECEIVED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("votes_received_total", "Votes received from bot lists by source and outcome"),
+        &["source", "outcome"]).unwrap());
+
+    /**
+    Requests dropped because of a missing or wrong token or signature, by source and reason
+    */
+    pub static ref UNAUTHORIZED_REQUESTS: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("vote_unauthorized_requests_total", "Vote requests rejected for failed authorization"),
+        &["source", "reason"]).unwrap());
+
+    /**
+    Vote requests accepted by the id of the key they were authorized with
+    */
+    pub static ref AUTH_KEY_MATCHED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("vote_auth_key_matched_total", "Vote requests authorized by source and key id"),
+        &["source", "key"]).unwrap());
+
+    /**
+    Duration of the deliveries to the sinks by route, sink and outcome
+    */
+    pub static ref FORWARD_LATENCY: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("vote_forward_duration_seconds", "Duration of deliveries to the sinks"),
+        &["route", "sink", "outcome"]).unwrap());
+
+    pub static ref DEAD_LETTERED: IntCounterVec = register(IntCounterVec::new(
+        Opts::new("votes_dead_lettered_total", "Votes moved to the dead letters"),
+        &["cause"]).unwrap());
+
+    pub static ref QUEUE_SIZE: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("vote_cache_size", "Votes waiting in the cache to be resent"), &["route", "sink"]).unwrap());
+
+    pub static ref OLDEST_QUEUED_AGE: GaugeVec = register(GaugeVec::new(
+        Opts::new("vote_cache_oldest_age_seconds", "Age of the oldest vote waiting in the cache"),
+        &["route", "sink"]).unwrap());
+
+    pub static ref DEAD_LETTERS: IntGaugeVec = register(IntGaugeVec::new(
+        Opts::new("vote_dead_letters", "Votes kept in the dead letters"), &["route", "sink"]).unwrap());
+
+    /**
+    Votes processed and delivered per resend execution
+    */
+    pub static ref RESEND_BATCH_SIZE: HistogramVec = register(HistogramVec::new(
+        HistogramOpts::new("vote_resend_batch_size", "Votes per resend execution")
+            .buckets(vec![0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0]),
+        &["kind"]).unwrap());
+}
+
+/**
+Registers all metrics up front so they are exported before their first change
+*/
+pub fn init() {
+    lazy_static::initialize(&VOTES_RECEIVED);
+    lazy_static::initialize(&UNAUTHORIZED_REQUESTS);
+    lazy_static::initialize(&AUTH_KEY_MATCHED);
+    lazy_static::initialize(&FORWARD_LATENCY);
+    lazy_static::initialize(&DEAD_LETTERED);
+    lazy_static::initialize(&QUEUE_SIZE);
+    lazy_static::initialize(&OLDEST_QUEUED_AGE);
+    lazy_static::initialize(&DEAD_LETTERS);
+    lazy_static::initialize(&RESEND_BATCH_SIZE);
+}
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).expect("Failed to register metric");
+    return collector;
+}
+
+/**
+Label of a forward result in the latency histogram
+*/
+pub fn forward_outcome(result: &ForwardResult) -> &'static str {
+    return match result {
+        ForwardResult::Delivered => "delivered",
+        ForwardResult::Retryable { .. } => "retryable",
+        ForwardResult::Rejected { .. } => "rejected",
+        ForwardResult::Unauthorized { .. } => "unauthorized",
+    };
+}
+
+pub fn count_unauthorized(source: &str, reason: &str) {
+    // admin requests carry no vote
+    if source != AUTH_SOURCE_ADMIN {
+        VOTES_RECEIVED.with_label_values(&[source, RECEIVED_UNAUTHORIZED]).inc();
+    }
+    UNAUTHORIZED_REQUESTS.with_label_values(&[source, reason]).inc();
+}
+
+pub fn count_auth_key(source: &str, key: &str) {
+    AUTH_KEY_MATCHED.with_label_values(&[source, key]).inc();
+}
+
+pub fn observe_resend_batch(processed: u32, delivered: u32) {
+    RESEND_BATCH_SIZE.with_label_values(&["processed"]).observe(processed as f64);
+    RESEND_BATCH_SIZE.with_label_values(&["delivered"]).observe(delivered as f64);
+}
+
+/**
+GET /metrics in the prometheus text format
+*/
+pub fn route() -> BoxedFilter<(Box<dyn Reply>,)> {
+    return warp::get()
+        .and(warp::path!("metrics"))
+        .map(|| {
+            let mut buffer = Vec::new();
+            TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer).unwrap();
+            let reply: Box<dyn Reply> = Box::new(warp::reply::with_header(
+                buffer, "Content-Type", prometheus::TEXT_FORMAT));
+            reply
+        })
+        .boxed();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+    Every metric listed in the README with its type and labels
+    */
+    const DOCUMENTED: &[(&str, &str, &[&str])] = &[
+        ("votes_received_total", "counter", &["source", "outcome"]),
+        ("vote_unauthorized_requests_total", "counter", &["source", "reason"]),
+        ("vote_auth_key_matched_total", "counter", &["source", "key"]),
+        ("vote_forward_duration_seconds", "histogram", &["route", "sink", "outcome"]),
+        ("votes_dead_lettered_total", "counter", &["cause"]),
+        ("vote_resend_batch_size", "histogram", &["kind"]),
+        ("vote_cache_size", "gauge", &["route", "sink"]),
+        ("vote_cache_oldest_age_seconds", "gauge", &["route", "sink"]),
+        ("vote_dead_letters", "gauge", &["route", "sink"]),
+    ];
+
+    #[tokio::test]
+    async fn scrape_exports_documented_metrics() {
+        init();
+        // labelled metrics are only exported once a value was recorded for a set of labels
+        VOTES_RECEIVED.with_label_values(&["metrics-test", RECEIVED_ACCEPTED]).inc();
+        count_unauthorized("metrics-test", "wrong_token");
+        count_auth_key("metrics-test", "primary");
+        FORWARD_LATENCY.with_label_values(&["metrics-test", "endpoint", forward_outcome(&ForwardResult::Delivered)])
+            .observe(0.1);
+        DEAD_LETTERED.with_label_values(&["rejected"]).inc();
+        observe_resend_batch(2, 1);
+        QUEUE_SIZE.with_label_values(&["metrics-test", "endpoint"]).set(1);
+        OLDEST_QUEUED_AGE.with_label_values(&["metrics-test", "endpoint"]).set(1.5);
+        DEAD_LETTERS.with_label_values(&["metrics-test", "endpoint"]).set(1);
+
+        let response = warp::test::request().method("GET").path("/metrics").reply(&route()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-type"], prometheus::TEXT_FORMAT);
+        let scraped = String::from_utf8(response.body().to_vec()).unwrap();
+
+        let mut exported: Vec<&str> = scraped.lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        exported.sort();
+        let mut documented: Vec<&str> = DOCUMENTED.iter().map(|(name, _, _)| *name).collect();
+        documented.sort();
+        assert_eq!(exported, documented);
+
+        for (name, kind, labels) in DOCUMENTED {
+            assert!(scraped.contains(format!("# TYPE {} {}\n", name, kind).as_str()), "{}", scraped);
+            let sample = if *kind == "histogram" { format!("{}_count{{", name) } else { format!("{}{{", name) };
+            let line = scraped.lines().find(|line| line.starts_with(sample.as_str()))
+                .unwrap_or_else(|| panic!("{} is not exported: {}", name, scraped));
+            // labels are exported sorted by name
+            let names: Vec<&str> = line[sample.len()..line.find('}').unwrap()].split(',')
+                .map(|label| label.split('=').next().unwrap())
+                .collect();
+            let mut labels = labels.to_vec();
+            labels.sort();
+            assert_eq!(names, labels, "{}", line);
+        }
+        assert!(scraped.contains("votes_received_total{outcome=\"unauthorized\",source=\"metrics-test\"} 1"));
+    }
+}
@@ -0,0 +1,152 @@
+use async_nats::{Client, ConnectErrorKind, ConnectOptions, HeaderMap};
+use async_nats::connection::State;
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::sync::Mutex;
+use crate::config;
+use crate::forward_result::ForwardResult;
+use crate::vote_request::{VoteEnvelope, VoteRequest};
+use crate::vote_sink::VoteSink;
+
+const HEADER_MESSAGE_ID: &str = "Nats-Msg-Id";
+
+/**
+A client connected to the server of the sink with the settings it was created from
+*/
+struct NatsClient {
+    endpoint: String,
+    auth_token: Option<String>,
+    client: Client,
+}
+
+/**
+Publishes votes wrapped in a `VoteEnvelope` to the subject of the sink suffixed with the id of the bot or guild.
+A publish counts as delivered once the server received it, the idempotency key is sent as
+`Nats-Msg-Id` so a JetStream stream on the subject drops repeated deliveries.
+*/
+pub struct NatsSink {
+    route: String,
+    name: String,
+    client: Mutex<Option<NatsClient>>,
+}
+
+impl NatsSink {
+    pub fn new(route: &str, name: &str) -> NatsSink {
+        return NatsSink {
+            route: route.to_owned(),
+            name: name.to_owned(),
+            client: Mutex::new(None),
+        };
+    }
+
+    /**
+    The connected client, connecting first if there is none or the settings changed
+    */
+    async fn client(&self, endpoint: &str, auth_token: Option<&str>) -> Result<Client, ForwardResult> {
+        let mut current = self.client.lock().await;
+        if let Some(open) = current.as_ref() {
+            if open.endpoint == endpoint && open.auth_token.as_deref() == auth_token {
+                return Ok(open.client.clone());
+            }
+        }
+        let mut options = ConnectOptions::new()
+            .connection_timeout(config::get().sink_timeout());
+        if let Some(auth_token) = auth_token {
+            options = options.token(auth_token.to_owned());
+        }
+        let client = match options.connect(endpoint).await {
+            Ok(client) => client,
+            Err(err) if err.kind() == ConnectErrorKind::AuthorizationViolation => {
+                return Err(ForwardResult::Unauthorized { reason: format!("Server refused the token: {}", err) });
+            }
+            Err(err) => {
+                warn!("Connecting nats sink {} of route {} failed!", self.name, self.route);
+                return Err(ForwardResult::Retryable { reason: format!("Connect failed: {}", err), retry_after: None });
+            }
+        };
+        info!("Connected nats sink {} of route {}", self.name, self.route);
+        *current = Some(NatsClient {
+            endpoint: endpoint.to_owned(),
+            auth_token: auth_token.map(str::to_owned),
+            client: client.clone(),
+        });
+        return Ok(client);
+    }
+}
+
+#[async_trait]
+impl VoteSink for NatsSink {
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+        let config = config::get();
+        let sink = config.sink(self.route.as_str(), self.name.as_str());
+        let target = sink.and_then(|sink| Some((sink.endpoint.as_deref()?, sink.subject.as_deref()?)));
+        let (endpoint, subject) = match target {
+            Some(target) => target,
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} is not configured", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let client = match self.client(endpoint, sink.and_then(|sink| sink.auth_token.as_deref())).await {
+            Ok(client) => client,
+            Err(result) => return result,
+        };
+        // published messages are buffered while reconnecting, don't let them pile up
+        if client.connection_state() != State::Connected {
+            return ForwardResult::Retryable {
+                reason: "Not connected to the server".to_owned(),
+                retry_after: None,
+            };
+        }
+        let payload = serde_json::to_vec(&VoteEnvelope::new(vote, self.route.as_str())).unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(key) = vote.idempotency_key.as_ref() {
+            headers.insert(HEADER_MESSAGE_ID, key.as_str());
+        }
+        let subject = vote_subject(subject, vote);
+        let publish = async {
+            client.publish_with_headers(subject, headers, payload.into()).await
+                .map_err(|err| err.to_string())?;
+            return client.flush().await.map_err(|err| err.to_string());
+        };
+        return match tokio::time::timeout(config.sink_timeout(), publish).await {
+            Ok(Ok(())) => ForwardResult::Delivered,
+            Ok(Err(err)) => {
+                warn!("Publishing to nats sink {} of route {} failed!", self.name, self.route);
+                ForwardResult::Retryable { reason: format!("Publish failed: {}", err), retry_after: None }
+            }
+            Err(_) => {
+                warn!("Publishing to nats sink {} of route {} timed out!", self.name, self.route);
+                ForwardResult::Retryable {
+                    reason: format!("Publish was not flushed within {}s", config.sink_timeout().as_secs()),
+                    retry_after: None,
+                }
+            }
+        };
+    }
+}
+
+/**
+The subject of the sink suffixed with the id of the bot or guild voted for
+*/
+fn vote_subject(subject: &str, vote: &VoteRequest) -> String {
+    return format!("{}.{}", subject, vote.target().0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subject_is_suffixed_with_bot_or_guild() {
+        let bot_vote: VoteRequest = serde_json::from_str(
+            r#"{"bot":"42","user":"1","type":"vote","isWeekend":false,"query":null,"src":"topgg"}"#).unwrap();
+        let guild_vote: VoteRequest = serde_json::from_str(
+            r#"{"bot":"0","guild":"9","kind":"guild","user":"1","type":"vote","isWeekend":false,"query":null,"src":"topgg"}"#)
+            .unwrap();
+        assert_eq!(vote_subject("votes", &bot_vote), "votes.42");
+        assert_eq!(vote_subject("votes", &guild_vote), "votes.9");
+    }
+}
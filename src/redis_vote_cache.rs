@@ -0,0 +1,766 @@
+use async_trait::async_trait;
+use log::{error, warn};
+use std::collections::HashMap;
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, RedisResult, Script};
+use crate::vote_cache::{VoteCache, CachedVote, DeadLetter, QueueFilter, now_millis, page_queued};
+use crate::vote_request::VoteRequest;
+
+/**
+Moves expired leases back into the queue and leases the first vote which is due to the owner token,
+returning its id and the vote
+
+KEYS: queue, leases, votes, owners | ARGV: now, lease expiry, owner token
+*/
+const CLAIM_SCRIPT: &str = r"
+local expired = redis.call('ZRANGEBYSCORE', KEYS[2], '-inf', ARGV[1])
+for _, id in ipairs(expired) do
+    redis.call('ZREM', KEYS[2], id)
+    redis.call('HDEL', KEYS[4], id)
+    redis.call('ZADD', KEYS[1], ARGV[1], id)
+end
+local head = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, 1)
+if #head == 0 then
+    return false
+end
+redis.call('ZREM', KEYS[1], head[1])
+redis.call('ZADD', KEYS[2], ARGV[2], head[1])
+redis.call('HSET', KEYS[4], head[1], ARGV[3])
+return {head[1], redis.call('HGET', KEYS[3], head[1])}
+";
+
+/**
+Stores the updated vote and puts it back into the queue, unless the lease was lost to another replica
+
+KEYS: queue, leases, votes, owners | ARGV: id, vote, next attempt, owner token
+*/
+const RELEASE_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[4], ARGV[1]) ~= ARGV[4] then
+    return 0
+end
+redis.call('ZREM', KEYS[2], ARGV[1])
+redis.call('HDEL', KEYS[4], ARGV[1])
+redis.call('HSET', KEYS[3], ARGV[1], ARGV[2])
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+return 1
+";
+
+/**
+Removes a delivered or unreadable vote, unless the lease was lost to another replica
+
+KEYS: leases, votes, enqueued, owners | ARGV: id, owner token
+*/
+const ACK_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[4], ARGV[1]) ~= ARGV[2] then
+    return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+redis.call('HDEL', KEYS[4], ARGV[1])
+redis.call('HDEL', KEYS[2], ARGV[1])
+redis.call('ZREM', KEYS[3], ARGV[1])
+return 1
+";
+
+/**
+Moves a leased vote to the dead letters, unless the lease was lost to another replica
+
+KEYS: leases, votes, enqueued, owners, dead, dead letters | ARGV: id, dead letter, owner token
+*/
+const DEAD_LETTER_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[4], ARGV[1]) ~= ARGV[3] then
+    return 0
+end
+redis.call('ZREM', KEYS[1], ARGV[1])
+redis.call('HDEL', KEYS[4], ARGV[1])
+redis.call('HDEL', KEYS[2], ARGV[1])
+redis.call('ZREM', KEYS[3], ARGV[1])
+redis.call('HSET', KEYS[6], ARGV[1], ARGV[2])
+redis.call('ZADD', KEYS[5], ARGV[1], ARGV[1])
+return 1
+";
+
+/**
+Puts a requeued dead letter back into the queue, unless another replica already did so
+
+KEYS: queue, votes, dead, dead letters, enqueued | ARGV: id, vote, next attempt, enqueued at
+*/
+const REQUEUE_SCRIPT: &str = r"
+if redis.call('HDEL', KEYS[4], ARGV[1]) == 0 then
+    return 0
+end
+redis.call('ZREM', KEYS[3], ARGV[1])
+redis.call('HSET', KEYS[2], ARGV[1], ARGV[2])
+redis.call('ZADD', KEYS[1], ARGV[3], ARGV[1])
+redis.call('ZADD', KEYS[5], ARGV[4], ARGV[1])
+return 1
+";
+
+/**
+Makes every queued vote due at the given time
+
+KEYS: queue | ARGV: now
+*/
+const EXPEDITE_SCRIPT: &str = r"
+local ids = redis.call('ZRANGE', KEYS[1], 0, -1)
+for _, id in ipairs(ids) do
+    redis.call('ZADD', KEYS[1], ARGV[1], id)
+end
+return #ids
+";
+
+/**
+Vote cache shared by all replicas through a Redis compatible server.
+
+Votes live in a hash keyed by id, their order in a sorted set scored by the time of
+their next attempt. Polling moves a due vote into a lease set until it is acknowledged
+or released; leases of replicas which died mid-delivery expire and the vote becomes
+claimable again. Every claim records a token of the claiming cache as owner of the lease,
+a replica whose lease expired and was claimed by another can't acknowledge, release or
+dead-letter the vote anymore. A second sorted set scored by the time of enqueueing tracks the age of
+the queue. Dead letters are kept in their own hash, ordered by a sorted set of their ids.
+*/
+pub struct RedisVoteCache {
+    connection: ConnectionManager,
+    id_key: String,
+    queue_key: String,
+    leases_key: String,
+    owners_key: String,
+    votes_key: String,
+    enqueued_key: String,
+    dead_key: String,
+    dead_letters_key: String,
+    lease_millis: u64,
+    owner: String,
+    held: HashMap<u64, String>,
+    claim_script: Script,
+    release_script: Script,
+    ack_script: Script,
+    dead_letter_script: Script,
+    requeue_script: Script,
+    expedite_script: Script,
+}
+
+impl RedisVoteCache {
+    pub async fn connect(url: &str, prefix: &str, lease_millis: u64) -> RedisResult<RedisVoteCache> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        return Ok(RedisVoteCache {
+            connection,
+            id_key: format!("{}:id", prefix),
+            queue_key: format!("{}:queue", prefix),
+            leases_key: format!("{}:leases", prefix),
+            owners_key: format!("{}:lease-owners", prefix),
+            votes_key: format!("{}:votes", prefix),
+            enqueued_key: format!("{}:enqueued", prefix),
+            dead_key: format!("{}:dead", prefix),
+            dead_letters_key: format!("{}:dead-letters", prefix),
+            lease_millis,
+            owner: format!("{:016x}", rand::random::<u64>()),
+            held: HashMap::new(),
+            claim_script: Script::new(CLAIM_SCRIPT),
+            release_script: Script::new(RELEASE_SCRIPT),
+            ack_script: Script::new(ACK_SCRIPT),
+            dead_letter_script: Script::new(DEAD_LETTER_SCRIPT),
+            requeue_script: Script::new(REQUEUE_SCRIPT),
+            expedite_script: Script::new(EXPEDITE_SCRIPT),
+        });
+    }
+
+    async fn try_cache_failed_vote(&self, vote: VoteRequest, error: String, next_attempt_at: u64)
+                                   -> RedisResult<()> {
+        let mut connection = self.connection.clone();
+        let id: u64 = connection.incr(self.id_key.as_str(), 1).await?;
+        let vote = CachedVote::first_failure(id, vote, error, next_attempt_at);
+        return redis::pipe().atomic()
+            .hset(self.votes_key.as_str(), id, serde_json::to_string(&vote).unwrap()).ignore()
+            .zadd(self.queue_key.as_str(), id, next_attempt_at).ignore()
+            .zadd(self.enqueued_key.as_str(), id, vote.enqueued_at).ignore()
+            .query_async(&mut connection).await;
+    }
+
+    /**
+    Claims due votes until one is readable, unreadable ones are removed so they don't come back
+    every lease period
+    */
+    async fn try_poll(&mut self) -> RedisResult<Option<CachedVote>> {
+        let mut connection = self.connection.clone();
+        loop {
+            let now = now_millis();
+            // each claim gets its own token, so a claim of this cache can't be mistaken for an older one
+            let token = format!("{}-{}", self.owner, now);
+            let claimed: Option<(u64, Option<String>)> = self.claim_script
+                .key(self.queue_key.as_str())
+                .key(self.leases_key.as_str())
+                .key(self.votes_key.as_str())
+                .key(self.owners_key.as_str())
+                .arg(now)
+                .arg(now + self.lease_millis)
+                .arg(token.as_str())
+                .invoke_async(&mut connection).await?;
+            let (id, vote) = match claimed {
+                Some(claimed) => claimed,
+                None => return Ok(None),
+            };
+            match vote.as_deref().map(serde_json::from_str::<CachedVote>) {
+                Some(Ok(vote)) => {
+                    self.held.insert(id, token);
+                    return Ok(Some(vote));
+                }
+                Some(Err(err)) => error!("Dropping unreadable vote {} from redis: {} | {}", id, err, vote.unwrap()),
+                None => error!("Dropping vote {} missing from redis", id),
+            }
+            let _: u8 = self.ack_script
+                .key(self.leases_key.as_str())
+                .key(self.votes_key.as_str())
+                .key(self.enqueued_key.as_str())
+                .key(self.owners_key.as_str())
+                .arg(id)
+                .arg(token.as_str())
+                .invoke_async(&mut connection).await?;
+        }
+    }
+
+    /**
+    Takes the owner token of a polled vote, None if it wasn't polled by this cache
+    */
+    fn release_lease(&mut self, id: u64) -> Option<String> {
+        let token = self.held.remove(&id);
+        if token.is_none() {
+            error!("Vote {} is not leased by this replica", id);
+        }
+        return token;
+    }
+
+    async fn try_dead_letter_failed_vote(&self, vote: VoteRequest, reason: String) -> RedisResult<()> {
+        let mut connection = self.connection.clone();
+        let id: u64 = connection.incr(self.id_key.as_str(), 1).await?;
+        let vote = CachedVote::first_failure(id, vote, reason.clone(), now_millis());
+        let letter = DeadLetter { dead_at: vote.enqueued_at, vote, reason };
+        return redis::pipe().atomic()
+            .hset(self.dead_letters_key.as_str(), id, serde_json::to_string(&letter).unwrap()).ignore()
+            .zadd(self.dead_key.as_str(), id, id).ignore()
+            .query_async(&mut connection).await;
+    }
+
+    async fn try_queued(&self, filter: &QueueFilter, offset: usize, limit: usize)
+                        -> RedisResult<(usize, Vec<CachedVote>)> {
+        let mut connection = self.connection.clone();
+        let votes: Vec<String> = connection.hvals(self.votes_key.as_str()).await?;
+        let votes: Vec<CachedVote> = votes.iter()
+            .filter_map(|vote| serde_json::from_str(vote.as_str()).ok())
+            .collect();
+        return Ok(page_queued(votes.iter(), filter, offset, limit));
+    }
+
+    async fn try_queued_vote(&self, id: u64) -> RedisResult<Option<CachedVote>> {
+        let mut connection = self.connection.clone();
+        let vote: Option<String> = connection.hget(self.votes_key.as_str(), id).await?;
+        return Ok(vote.and_then(|vote| serde_json::from_str(vote.as_str()).ok()));
+    }
+
+    async fn try_remove_queued(&self, id: u64) -> RedisResult<bool> {
+        let mut connection = self.connection.clone();
+        let (removed,): (usize,) = redis::pipe().atomic()
+            .zrem(self.queue_key.as_str(), id).ignore()
+            .zrem(self.leases_key.as_str(), id).ignore()
+            .hdel(self.owners_key.as_str(), id).ignore()
+            .zrem(self.enqueued_key.as_str(), id).ignore()
+            .hdel(self.votes_key.as_str(), id)
+            .query_async(&mut connection).await?;
+        return Ok(removed > 0);
+    }
+
+    async fn try_dead_letters(&self, offset: usize, limit: usize) -> RedisResult<Vec<DeadLetter>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+        let mut connection = self.connection.clone();
+        let ids: Vec<u64> = connection.zrange(self.dead_key.as_str(), offset as isize,
+                                              (offset + limit - 1) as isize).await?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let letters: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(self.dead_letters_key.as_str())
+            .arg(ids)
+            .query_async(&mut connection).await?;
+        return Ok(letters.into_iter()
+            .flatten()
+            .filter_map(|letter| serde_json::from_str(letter.as_str()).ok())
+            .collect());
+    }
+
+    async fn try_requeue_dead_letter(&self, id: u64) -> RedisResult<bool> {
+        let mut connection = self.connection.clone();
+        let letter: Option<String> = connection.hget(self.dead_letters_key.as_str(), id).await?;
+        let letter: DeadLetter = match letter.and_then(|letter| serde_json::from_str(letter.as_str()).ok()) {
+            Some(letter) => letter,
+            None => return Ok(false),
+        };
+        let vote = letter.into_requeued();
+        let moved: u8 = self.requeue_script
+            .key(self.queue_key.as_str())
+            .key(self.votes_key.as_str())
+            .key(self.dead_key.as_str())
+            .key(self.dead_letters_key.as_str())
+            .key(self.enqueued_key.as_str())
+            .arg(id)
+            .arg(serde_json::to_string(&vote).unwrap())
+            .arg(vote.next_attempt_at)
+            .arg(vote.enqueued_at)
+            .invoke_async(&mut connection).await?;
+        return Ok(moved > 0);
+    }
+
+    async fn try_purge_dead_letters(&self, id: Option<u64>) -> RedisResult<usize> {
+        let mut connection = self.connection.clone();
+        return match id {
+            Some(id) => {
+                let (removed, _): (usize, usize) = redis::pipe().atomic()
+                    .hdel(self.dead_letters_key.as_str(), id)
+                    .zrem(self.dead_key.as_str(), id)
+                    .query_async(&mut connection).await?;
+                Ok(removed)
+            }
+            None => {
+                let (count, _): (usize, usize) = redis::pipe().atomic()
+                    .hlen(self.dead_letters_key.as_str())
+                    .del(&[self.dead_letters_key.as_str(), self.dead_key.as_str()])
+                    .query_async(&mut connection).await?;
+                Ok(count)
+            }
+        };
+    }
+}
+
+#[async_trait]
+impl VoteCache for RedisVoteCache {
+    async fn cache_failed_vote(&mut self, vote: VoteRequest, error: String, next_attempt_at: u64) {
+        if let Err(err) = self.try_cache_failed_vote(vote, error, next_attempt_at).await {
+            error!("Failed to add vote to redis: {}", err);
+        }
+    }
+
+    async fn return_failed_retry(&mut self, vote: CachedVote) {
+        let token = match self.release_lease(vote.id) {
+            Some(token) => token,
+            None => return,
+        };
+        let mut connection = self.connection.clone();
+        let result: RedisResult<u8> = self.release_script
+            .key(self.queue_key.as_str())
+            .key(self.leases_key.as_str())
+            .key(self.votes_key.as_str())
+            .key(self.owners_key.as_str())
+            .arg(vote.id)
+            .arg(serde_json::to_string(&vote).unwrap())
+            .arg(vote.next_attempt_at)
+            .arg(token)
+            .invoke_async(&mut connection).await;
+        match result {
+            Ok(0) => error!("Lease of vote {} expired before it was returned", vote.id),
+            Ok(_) => {}
+            Err(err) => error!("Failed to return vote {} to redis: {}", vote.id, err),
+        }
+    }
+
+    async fn poll(&mut self) -> Option<CachedVote> {
+        return match self.try_poll().await {
+            Ok(vote) => vote,
+            Err(err) => {
+                error!("Failed to poll vote from redis: {}", err);
+                None
+            }
+        };
+    }
+
+    async fn ack(&mut self, vote: &CachedVote) {
+        let token = match self.release_lease(vote.id) {
+            Some(token) => token,
+            None => return,
+        };
+        let mut connection = self.connection.clone();
+        let result: RedisResult<u8> = self.ack_script
+            .key(self.leases_key.as_str())
+            .key(self.votes_key.as_str())
+            .key(self.enqueued_key.as_str())
+            .key(self.owners_key.as_str())
+            .arg(vote.id)
+            .arg(token)
+            .invoke_async(&mut connection).await;
+        match result {
+            Ok(0) => warn!("Lease of vote {} was lost before it was acknowledged, it may be delivered again", vote.id),
+            Ok(_) => {}
+            Err(err) => error!("Failed to remove vote {} from redis: {}", vote.id, err),
+        }
+    }
+
+    async fn size(&self) -> usize {
+        let mut connection = self.connection.clone();
+        let size: RedisResult<usize> = connection.hlen(self.votes_key.as_str()).await;
+        return size.unwrap_or_else(|err| {
+            error!("Failed to count votes in redis: {}", err);
+            0
+        });
+    }
+
+    async fn check_ready(&self) -> Result<(), String> {
+        let mut connection = self.connection.clone();
+        let result: RedisResult<String> = redis::cmd("PING").query_async(&mut connection).await;
+        return result
+            .map(|_| ())
+            .map_err(|err| format!("Vote cache redis unavailable: {}", err));
+    }
+
+    async fn oldest_enqueued_at(&self) -> Option<u64> {
+        let mut connection = self.connection.clone();
+        let oldest: RedisResult<Vec<(u64, f64)>> = connection.zrange_withscores(self.enqueued_key.as_str(), 0, 0).await;
+        return match oldest {
+            Ok(oldest) => oldest.first().map(|(_, enqueued_at)| *enqueued_at as u64),
+            Err(err) => {
+                error!("Failed to read oldest vote in redis: {}", err);
+                None
+            }
+        };
+    }
+
+    async fn dead_letter(&mut self, vote: CachedVote, reason: String) {
+        let id = vote.id;
+        let token = match self.release_lease(id) {
+            Some(token) => token,
+            None => return,
+        };
+        let mut connection = self.connection.clone();
+        let letter = DeadLetter { vote, reason, dead_at: now_millis() };
+        let result: RedisResult<u8> = self.dead_letter_script
+            .key(self.leases_key.as_str())
+            .key(self.votes_key.as_str())
+            .key(self.enqueued_key.as_str())
+            .key(self.owners_key.as_str())
+            .key(self.dead_key.as_str())
+            .key(self.dead_letters_key.as_str())
+            .arg(id)
+            .arg(serde_json::to_string(&letter).unwrap())
+            .arg(token)
+            .invoke_async(&mut connection).await;
+        match result {
+            Ok(0) => warn!("Lease of vote {} was lost before it was dead-lettered", id),
+            Ok(_) => {}
+            Err(err) => error!("Failed to dead-letter vote {} in redis: {}", id, err),
+        }
+    }
+
+    async fn dead_letter_failed_vote(&mut self, vote: VoteRequest, reason: String) {
+        if let Err(err) = self.try_dead_letter_failed_vote(vote, reason).await {
+            error!("Failed to dead-letter rejected vote in redis: {}", err);
+        }
+    }
+
+    async fn dead_letters(&self, offset: usize, limit: usize) -> Vec<DeadLetter> {
+        return self.try_dead_letters(offset, limit).await.unwrap_or_else(|err| {
+            error!("Failed to list dead letters in redis: {}", err);
+            Vec::new()
+        });
+    }
+
+    async fn dead_letter_count(&self) -> usize {
+        let mut connection = self.connection.clone();
+        let count: RedisResult<usize> = connection.hlen(self.dead_letters_key.as_str()).await;
+        return count.unwrap_or_else(|err| {
+            error!("Failed to count dead letters in redis: {}", err);
+            0
+        });
+    }
+
+    async fn requeue_dead_letter(&mut self, id: u64) -> bool {
+        return self.try_requeue_dead_letter(id).await.unwrap_or_else(|err| {
+            error!("Failed to requeue dead letter {} in redis: {}", id, err);
+            false
+        });
+    }
+
+    async fn purge_dead_letters(&mut self, id: Option<u64>) -> usize {
+        return self.try_purge_dead_letters(id).await.unwrap_or_else(|err| {
+            error!("Failed to purge dead letters in redis: {}", err);
+            0
+        });
+    }
+
+    async fn queued(&self, filter: &QueueFilter, offset: usize, limit: usize) -> (usize, Vec<CachedVote>) {
+        return self.try_queued(filter, offset, limit).await.unwrap_or_else(|err| {
+            error!("Failed to list votes in redis: {}", err);
+            (0, Vec::new())
+        });
+    }
+
+    async fn queued_vote(&self, id: u64) -> Option<CachedVote> {
+        return self.try_queued_vote(id).await.unwrap_or_else(|err| {
+            error!("Failed to read vote {} from redis: {}", id, err);
+            None
+        });
+    }
+
+    async fn remove_queued(&mut self, id: u64) -> bool {
+        return self.try_remove_queued(id).await.unwrap_or_else(|err| {
+            error!("Failed to delete vote {} from redis: {}", id, err);
+            false
+        });
+    }
+
+    async fn expedite_queued(&mut self) -> usize {
+        let mut connection = self.connection.clone();
+        let result: RedisResult<usize> = self.expedite_script
+            .key(self.queue_key.as_str())
+            .arg(now_millis())
+            .invoke_async(&mut connection).await;
+        return result.unwrap_or_else(|err| {
+            error!("Failed to expedite votes in redis: {}", err);
+            0
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mlua::{Lua, Value};
+
+    /**
+    The subset of redis commands used by the scripts, on plain lua tables
+    */
+    const MOCK_REDIS: &str = r"
+local data = {}
+local function get(key)
+    data[key] = data[key] or {}
+    return data[key]
+end
+local function range(key, max, offset, count)
+    local members = {}
+    for member, score in pairs(get(key)) do
+        if score <= tonumber(max) then
+            table.insert(members, member)
+        end
+    end
+    table.sort(members, function(a, b) return get(key)[a] < get(key)[b] end)
+    local result = {}
+    for i = (offset or 0) + 1, math.min(#members, (offset or 0) + (count or #members)) do
+        table.insert(result, members[i])
+    end
+    return result
+end
+redis = {}
+function redis.call(command, key, ...)
+    local args = {...}
+    if command == 'ZADD' then
+        get(key)[tostring(args[2])] = tonumber(args[1])
+        return 1
+    elseif command == 'ZREM' or command == 'HDEL' then
+        local existed = get(key)[tostring(args[1])] ~= nil
+        get(key)[tostring(args[1])] = nil
+        return existed and 1 or 0
+    elseif command == 'ZRANGEBYSCORE' then
+        return range(key, args[2], args[4], args[5])
+    elseif command == 'HSET' then
+        get(key)[tostring(args[1])] = args[2]
+        return 1
+    elseif command == 'HGET' then
+        local value = get(key)[tostring(args[1])]
+        if value == nil then
+            return false
+        end
+        return value
+    end
+    error('unsupported command ' .. command)
+end
+";
+
+    const QUEUE: &str = "queue";
+    const LEASES: &str = "leases";
+    const VOTES: &str = "votes";
+    const OWNERS: &str = "owners";
+    const ENQUEUED: &str = "enqueued";
+    const DEAD: &str = "dead";
+    const DEAD_LETTERS: &str = "dead-letters";
+
+    fn mock_redis() -> Lua {
+        let lua = Lua::new();
+        lua.load(MOCK_REDIS).exec().unwrap();
+        return lua;
+    }
+
+    fn eval<'lua>(lua: &'lua Lua, script: &str, keys: &[&str], args: &[&str]) -> Value<'lua> {
+        lua.globals().set("KEYS", keys.to_vec()).unwrap();
+        lua.globals().set("ARGV", args.to_vec()).unwrap();
+        return lua.load(script).eval().unwrap();
+    }
+
+    fn call<'lua>(lua: &'lua Lua, command: &str, key: &str, args: &[&str]) -> Value<'lua> {
+        let redis: mlua::Table = lua.globals().get("redis").unwrap();
+        let call: mlua::Function = redis.get("call").unwrap();
+        let mut call_args = vec![command, key];
+        call_args.extend_from_slice(args);
+        return call.call(call_args.into_iter().collect::<mlua::Variadic<_>>()).unwrap();
+    }
+
+    fn hget(lua: &Lua, key: &str, field: &str) -> Option<String> {
+        return match call(lua, "HGET", key, &[field]) {
+            Value::String(value) => Some(value.to_str().unwrap().to_owned()),
+            _ => None,
+        };
+    }
+
+    fn enqueue(lua: &Lua, id: &str, vote: &str, next_attempt_at: &str) {
+        call(lua, "HSET", VOTES, &[id, vote]);
+        call(lua, "ZADD", QUEUE, &[next_attempt_at, id]);
+        call(lua, "ZADD", ENQUEUED, &[next_attempt_at, id]);
+    }
+
+    fn claim(lua: &Lua, now: u64, expiry: u64, token: &str) -> Option<(String, Option<String>)> {
+        let claimed = match eval(lua, CLAIM_SCRIPT, &[QUEUE, LEASES, VOTES, OWNERS],
+                                 &[now.to_string().as_str(), expiry.to_string().as_str(), token]) {
+            Value::Table(claimed) => claimed,
+            _ => return None,
+        };
+        let id: String = claimed.get(1).unwrap();
+        let vote: Option<String> = match claimed.get::<_, Value>(2).unwrap() {
+            Value::String(vote) => Some(vote.to_str().unwrap().to_owned()),
+            _ => None,
+        };
+        return Some((id, vote));
+    }
+
+    fn ack(lua: &Lua, id: &str, token: &str) -> i64 {
+        return match eval(lua, ACK_SCRIPT, &[LEASES, VOTES, ENQUEUED, OWNERS], &[id, token]) {
+            Value::Integer(result) => result,
+            other => panic!("unexpected ack result {:?}", other),
+        };
+    }
+
+    #[test]
+    fn claim_leases_due_vote_to_owner() {
+        let lua = mock_redis();
+        enqueue(&lua, "1", "{}", "100");
+        enqueue(&lua, "2", "{}", "500");
+
+        assert_eq!(claim(&lua, 200, 1200, "a"), Some(("1".to_owned(), Some("{}".to_owned()))));
+        assert_eq!(hget(&lua, OWNERS, "1").as_deref(), Some("a"));
+        assert_eq!(claim(&lua, 200, 1200, "a"), None);
+    }
+
+    #[test]
+    fn claim_returns_id_of_missing_vote() {
+        let lua = mock_redis();
+        call(&lua, "ZADD", QUEUE, &["100", "1"]);
+
+        assert_eq!(claim(&lua, 200, 1200, "a"), Some(("1".to_owned(), None)));
+        assert_eq!(ack(&lua, "1", "a"), 1);
+        assert_eq!(claim(&lua, 2000, 3000, "a"), None);
+    }
+
+    #[test]
+    fn expired_lease_moves_to_new_owner() {
+        let lua = mock_redis();
+        enqueue(&lua, "1", "{}", "100");
+        claim(&lua, 200, 1200, "a").unwrap();
+
+        assert_eq!(claim(&lua, 1300, 2300, "b").map(|(id, _)| id).as_deref(), Some("1"));
+        assert_eq!(hget(&lua, OWNERS, "1").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn ack_requires_lease_owner() {
+        let lua = mock_redis();
+        enqueue(&lua, "1", "{}", "100");
+        claim(&lua, 200, 1200, "a").unwrap();
+        claim(&lua, 1300, 2300, "b").unwrap();
+
+        assert_eq!(ack(&lua, "1", "a"), 0);
+        assert_eq!(hget(&lua, VOTES, "1").as_deref(), Some("{}"));
+        assert_eq!(ack(&lua, "1", "b"), 1);
+        assert_eq!(hget(&lua, VOTES, "1"), None);
+        assert_eq!(hget(&lua, OWNERS, "1"), None);
+    }
+
+    #[test]
+    fn release_requires_lease_owner() {
+        let lua = mock_redis();
+        enqueue(&lua, "1", "{}", "100");
+        claim(&lua, 200, 1200, "a").unwrap();
+        claim(&lua, 1300, 2300, "b").unwrap();
+
+        let keys = [QUEUE, LEASES, VOTES, OWNERS];
+        assert!(matches!(eval(&lua, RELEASE_SCRIPT, &keys, &["1", "stale", "5000", "a"]), Value::Integer(0)));
+        assert_eq!(hget(&lua, VOTES, "1").as_deref(), Some("{}"));
+        assert!(matches!(eval(&lua, RELEASE_SCRIPT, &keys, &["1", "retried", "5000", "b"]), Value::Integer(1)));
+        assert_eq!(hget(&lua, VOTES, "1").as_deref(), Some("retried"));
+        assert_eq!(claim(&lua, 4000, 5000, "c"), None);
+        assert_eq!(claim(&lua, 5000, 6000, "c"), Some(("1".to_owned(), Some("retried".to_owned()))));
+    }
+
+    #[test]
+    fn dead_letter_requires_lease_owner() {
+        let lua = mock_redis();
+        enqueue(&lua, "1", "{}", "100");
+        claim(&lua, 200, 1200, "a").unwrap();
+        claim(&lua, 1300, 2300, "b").unwrap();
+
+        let keys = [LEASES, VOTES, ENQUEUED, OWNERS, DEAD, DEAD_LETTERS];
+        assert!(matches!(eval(&lua, DEAD_LETTER_SCRIPT, &keys, &["1", "letter", "a"]), Value::Integer(0)));
+        assert_eq!(hget(&lua, DEAD_LETTERS, "1"), None);
+        assert!(matches!(eval(&lua, DEAD_LETTER_SCRIPT, &keys, &["1", "letter", "b"]), Value::Integer(1)));
+        assert_eq!(hget(&lua, DEAD_LETTERS, "1").as_deref(), Some("letter"));
+        assert_eq!(hget(&lua, VOTES, "1"), None);
+    }
+
+    fn test_vote() -> VoteRequest {
+        return serde_json::from_str(r#"{"bot":"1","user":"2","type":"upvote","isWeekend":false,"query":null,"src":null}"#)
+            .unwrap();
+    }
+
+    /**
+    Runs against the redis at VOTE_TEST_REDIS_URL, skipped when it isn't set
+    */
+    async fn connect_test_caches(lease_millis: u64) -> Option<(RedisVoteCache, RedisVoteCache)> {
+        let url = std::env::var("VOTE_TEST_REDIS_URL").ok()?;
+        let prefix = format!("vote-test:{:016x}", rand::random::<u64>());
+        let first = RedisVoteCache::connect(url.as_str(), prefix.as_str(), lease_millis).await.unwrap();
+        let second = RedisVoteCache::connect(url.as_str(), prefix.as_str(), lease_millis).await.unwrap();
+        return Some((first, second));
+    }
+
+    #[tokio::test]
+    async fn lost_lease_is_not_acknowledged() {
+        let (mut first, mut second) = match connect_test_caches(50).await {
+            Some(caches) => caches,
+            None => return,
+        };
+        first.cache_failed_vote(test_vote(), "failed".to_owned(), 0).await;
+        let vote = first.poll().await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let stolen = second.poll().await.unwrap();
+        assert_eq!(stolen.id, vote.id);
+
+        first.ack(&vote).await;
+        assert_eq!(first.size().await, 1);
+        second.ack(&stolen).await;
+        assert_eq!(second.size().await, 0);
+    }
+
+    #[tokio::test]
+    async fn unreadable_vote_is_removed() {
+        let (mut cache, _) = match connect_test_caches(60_000).await {
+            Some(caches) => caches,
+            None => return,
+        };
+        let mut connection = cache.connection.clone();
+        let _: () = redis::pipe()
+            .hset(cache.votes_key.as_str(), 1_000_000, "not a vote").ignore()
+            .zadd(cache.queue_key.as_str(), 1_000_000, 0).ignore()
+            .query_async(&mut connection).await.unwrap();
+        cache.cache_failed_vote(test_vote(), "failed".to_owned(), 0).await;
+
+        let vote = cache.poll().await.unwrap();
+        assert_eq!(vote.vote.user.0, 2);
+        assert_eq!(cache.size().await, 1);
+    }
+}
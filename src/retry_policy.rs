@@ -0,0 +1,121 @@
+use core::time::Duration;
+use crate::config::{self, Config};
+use crate::vote_cache::{CachedVote, now_millis};
+
+/**
+Delay before the next delivery attempt of a vote which already failed `attempts` times.
+
+Doubles with every attempt starting at VOTE_RETRY_BACKOFF_BASE, is spread by up to
+VOTE_RETRY_BACKOFF_JITTER in both directions so votes cached during the same outage don't
+retry in lockstep, and is capped at VOTE_RETRY_BACKOFF_MAX.
+*/
+pub fn backoff_delay(attempts: u32) -> Duration {
+    return jittered_delay(&config::get(), attempts, rand::random::<f64>());
+}
+
+/**
+The backoff delay for a `random` number in [0, 1), which picks the jitter. The cap is applied
+after the jitter so it is never exceeded
+*/
+fn jittered_delay(config: &Config, attempts: u32, random: f64) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(31);
+    let delay = config.retry_backoff_base()
+        .checked_mul(1 << exponent)
+        .unwrap_or(config.retry_backoff_max())
+        .min(config.retry_backoff_max());
+    let jitter = config.retry_backoff_jitter.clamp(0.0, 1.0);
+    let factor = 1.0 + jitter * (random * 2.0 - 1.0);
+    return delay.mul_f64(factor).min(config.retry_backoff_max());
+}
+
+/**
+Unix timestamp in milliseconds at which a vote which failed `attempts` times may be retried.
+A delay requested by the endpoint is honoured up to VOTE_RETRY_BACKOFF_MAX.
+*/
+pub fn next_attempt_at(attempts: u32, retry_after: Option<Duration>) -> u64 {
+    let delay = match retry_after {
+        Some(retry_after) => backoff_delay(attempts).max(retry_after.min(config::get().retry_backoff_max())),
+        None => backoff_delay(attempts),
+    };
+    return now_millis() + delay.as_millis() as u64;
+}
+
+/**
+Reason to give up on the vote if it exhausted VOTE_RETRY_MAX_ATTEMPTS or VOTE_RETRY_MAX_AGE
+*/
+pub fn exhausted_reason(vote: &CachedVote) -> Option<String> {
+    return exhausted_reason_at(&config::get(), vote, now_millis());
+}
+
+fn exhausted_reason_at(config: &Config, vote: &CachedVote, now: u64) -> Option<String> {
+    let last_error = vote.last_error.clone().unwrap_or("unknown error".to_owned());
+    if config.retry_max_attempts > 0 && vote.attempts >= config.retry_max_attempts {
+        return Some(format!("Gave up after {} attempts: {}", vote.attempts, last_error));
+    }
+    let age = Duration::from_millis(now.saturating_sub(vote.enqueued_at));
+    if !config.retry_max_age().is_zero() && age >= config.retry_max_age() {
+        return Some(format!("Gave up after {}s: {}", age.as_secs(), last_error));
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32, max_age: u64) -> Config {
+        return Config {
+            retry_backoff_base: 5,
+            retry_backoff_max: 60,
+            retry_backoff_jitter: 0.2,
+            retry_max_attempts: max_attempts,
+            retry_max_age: max_age,
+            ..Config::default()
+        };
+    }
+
+    fn cached_vote(attempts: u32, enqueued_at: u64) -> CachedVote {
+        let vote = r#"{"bot":"1","user":"1","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}"#;
+        let mut vote = CachedVote::first_failure(1, serde_json::from_str(vote).unwrap(), "failed".to_owned(), 0);
+        vote.attempts = attempts;
+        vote.enqueued_at = enqueued_at;
+        return vote;
+    }
+
+    #[test]
+    fn backoff_doubles_with_every_attempt() {
+        let config = policy(0, 0);
+        // 0.5 is the middle of the jitter range, so no jitter
+        let delays: Vec<u64> = (1..=4).map(|attempts| jittered_delay(&config, attempts, 0.5).as_secs()).collect();
+        assert_eq!(delays, vec![5, 10, 20, 40]);
+        assert_eq!(jittered_delay(&config, 1, 0.0), Duration::from_secs(4));
+        assert_eq!(jittered_delay(&config, 1, 1.0), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn backoff_never_exceeds_max_with_jitter() {
+        let config = policy(0, 0);
+        for attempts in [5, 6, 32, u32::MAX] {
+            assert_eq!(jittered_delay(&config, attempts, 0.99), Duration::from_secs(60));
+            assert!(jittered_delay(&config, attempts, 0.0) >= Duration::from_secs(48));
+        }
+    }
+
+    #[test]
+    fn zero_limits_never_exhaust_a_vote() {
+        let config = policy(0, 0);
+        assert_eq!(exhausted_reason_at(&config, &cached_vote(1000, 0), now_millis()), None);
+    }
+
+    #[test]
+    fn vote_is_exhausted_after_max_attempts_or_max_age() {
+        let now = now_millis();
+        let config = policy(3, 0);
+        assert_eq!(exhausted_reason_at(&config, &cached_vote(2, 0), now), None);
+        assert_eq!(exhausted_reason_at(&config, &cached_vote(3, 0), now).unwrap(), "Gave up after 3 attempts: failed");
+
+        let config = policy(0, 60);
+        assert_eq!(exhausted_reason_at(&config, &cached_vote(100, now - 59_000), now), None);
+        assert_eq!(exhausted_reason_at(&config, &cached_vote(100, now - 60_000), now).unwrap(), "Gave up after 60s: failed");
+    }
+}
@@ -0,0 +1,506 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use log::error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use crate::vote_cache::{VoteCache, CachedVote, DeadLetter, QueueFilter, now_millis};
+use crate::vote_request::VoteRequest;
+
+const DATABASE_FILE: &str = "votes.db";
+
+/**
+Schema migrations, the index of each statement batch is its resulting user_version - 1
+*/
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE votes (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        source TEXT,
+        bot TEXT NOT NULL,
+        user TEXT NOT NULL,
+        vote TEXT NOT NULL,
+        enqueued_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        claimed_at INTEGER
+    );",
+    "ALTER TABLE votes ADD COLUMN next_attempt_at INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX votes_next_attempt_at ON votes (next_attempt_at);",
+    "CREATE TABLE dead_letters (
+        id INTEGER PRIMARY KEY,
+        source TEXT,
+        bot TEXT NOT NULL,
+        user TEXT NOT NULL,
+        vote TEXT NOT NULL,
+        enqueued_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL,
+        last_error TEXT,
+        reason TEXT NOT NULL,
+        dead_at INTEGER NOT NULL
+    );",
+    "ALTER TABLE votes ADD COLUMN history TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE dead_letters ADD COLUMN history TEXT NOT NULL DEFAULT '[]';
+    CREATE INDEX votes_bot ON votes (bot);
+    CREATE INDEX votes_user ON votes (user);",
+];
+
+const VOTE_COLUMNS: &str = "id, vote, enqueued_at, attempts, last_error, next_attempt_at, history";
+
+/**
+Vote cache backed by an embedded SQLite database in the cache directory.
+
+Each vote is a row ordered by insertion; polling claims the oldest unclaimed row which is due
+and only an acknowledgement deletes it, so a crash mid-delivery leaves the vote
+in place to be retried after the next start. Dead letters are moved into their own
+table within the same transaction as their removal from the queue.
+*/
+pub struct SqliteVoteCache {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteVoteCache {
+    pub fn open(dir: &Path) -> Result<SqliteVoteCache, Box<dyn Error>> {
+        fs::create_dir_all(dir)?;
+        let mut connection = Connection::open(dir.join(DATABASE_FILE))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.pragma_update(None, "synchronous", "FULL")?;
+        migrate(&mut connection)?;
+        // Claims only live as long as the process holding them
+        connection.execute("UPDATE votes SET claimed_at = NULL WHERE claimed_at IS NOT NULL", [])?;
+        return Ok(SqliteVoteCache {
+            connection: Arc::new(Mutex::new(connection)),
+        });
+    }
+
+    /**
+    Runs the statements on the blocking thread pool, as every write waits for the disk to sync and
+    would otherwise stall the other tasks of the runtime
+    */
+    async fn run<T, F>(&self, statements: F) -> rusqlite::Result<T>
+        where T: Send + 'static, F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static {
+        let connection = self.connection.clone();
+        return match tokio::task::spawn_blocking(move || statements(&mut connection.lock().unwrap())).await {
+            Ok(result) => result,
+            // a panic of the statements is passed on as if they had run on this task
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        };
+    }
+}
+
+#[async_trait]
+impl VoteCache for SqliteVoteCache {
+    async fn cache_failed_vote(&mut self, vote: VoteRequest, error: String, next_attempt_at: u64) {
+        let vote = CachedVote::first_failure(0, vote, error, next_attempt_at);
+        let result = self.run(move |connection| insert_vote(connection, &vote)).await;
+        if let Err(err) = result {
+            error!("Failed to insert vote into cache database: {}", err);
+        }
+    }
+
+    async fn return_failed_retry(&mut self, vote: CachedVote) {
+        let id = vote.id;
+        let result = self.run(move |connection| connection.execute(
+            "UPDATE votes SET attempts = ?2, last_error = ?3, next_attempt_at = ?4, history = ?5, claimed_at = NULL
+             WHERE id = ?1",
+            params![vote.id as i64, vote.attempts, vote.last_error, vote.next_attempt_at as i64,
+                    serde_json::to_string(&vote.history).unwrap()],
+        )).await;
+        if let Err(err) = result {
+            error!("Failed to release vote {} in cache database: {}", id, err);
+        }
+    }
+
+    async fn poll(&mut self) -> Option<CachedVote> {
+        let result = self.run(|connection| connection.query_row(
+            format!("UPDATE votes SET claimed_at = ?1
+                     WHERE id = (SELECT id FROM votes WHERE claimed_at IS NULL AND next_attempt_at <= ?1
+                                 ORDER BY id LIMIT 1)
+                     RETURNING {}", VOTE_COLUMNS).as_str(),
+            params![now_millis() as i64],
+            read_cached_vote,
+        ).optional()).await;
+        return match result {
+            Ok(vote) => vote,
+            Err(err) => {
+                error!("Failed to poll vote from cache database: {}", err);
+                None
+            }
+        };
+    }
+
+    async fn ack(&mut self, vote: &CachedVote) {
+        let id = vote.id;
+        let result = self.run(move |connection| {
+            connection.execute("DELETE FROM votes WHERE id = ?1", params![id as i64])
+        }).await;
+        if let Err(err) = result {
+            error!("Failed to delete vote {} from cache database: {}", id, err);
+        }
+    }
+
+    async fn size(&self) -> usize {
+        return self.run(|connection| connection.query_row("SELECT COUNT(*) FROM votes", [], |row| row.get::<_, i64>(0)))
+            .await
+            .unwrap_or_else(|err| {
+                error!("Failed to count votes in cache database: {}", err);
+                0
+            }) as usize;
+    }
+
+    async fn check_ready(&self) -> Result<(), String> {
+        return self.run(|connection| connection.query_row("SELECT 1", [], |row| row.get::<_, i64>(0)))
+            .await
+            .map(|_| ())
+            .map_err(|err| format!("Vote cache database unavailable: {}", err));
+    }
+
+    async fn oldest_enqueued_at(&self) -> Option<u64> {
+        return self.run(|connection| {
+            connection.query_row("SELECT MIN(enqueued_at) FROM votes", [], |row| row.get::<_, Option<i64>>(0))
+        }).await
+            .unwrap_or_else(|err| {
+                error!("Failed to read oldest vote in cache database: {}", err);
+                None
+            })
+            .map(|enqueued_at| enqueued_at as u64);
+    }
+
+    async fn dead_letter(&mut self, vote: CachedVote, reason: String) {
+        let id = vote.id;
+        let result = self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO dead_letters (id, source, bot, user, vote, enqueued_at, attempts, last_error,
+                                           history, reason, dead_at)
+                 SELECT id, source, bot, user, vote, enqueued_at, ?2, ?3, ?4, ?5, ?6 FROM votes WHERE id = ?1",
+                params![vote.id as i64, vote.attempts, vote.last_error, serde_json::to_string(&vote.history).unwrap(),
+                        reason, now_millis() as i64],
+            )?;
+            transaction.execute("DELETE FROM votes WHERE id = ?1", params![vote.id as i64])?;
+            transaction.commit()
+        }).await;
+        if let Err(err) = result {
+            error!("Failed to dead-letter vote {} in cache database: {}", id, err);
+        }
+    }
+
+    async fn dead_letter_failed_vote(&mut self, vote: VoteRequest, reason: String) {
+        let now = now_millis() as i64;
+        let result = self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            insert_vote(&transaction, &CachedVote::first_failure(0, vote, reason.clone(), now as u64))?;
+            let id = transaction.last_insert_rowid();
+            transaction.execute(
+                "INSERT INTO dead_letters (id, source, bot, user, vote, enqueued_at, attempts, last_error,
+                                           history, reason, dead_at)
+                 SELECT id, source, bot, user, vote, enqueued_at, attempts, last_error, history, ?2, ?3 FROM votes
+                 WHERE id = ?1",
+                params![id, reason, now],
+            )?;
+            transaction.execute("DELETE FROM votes WHERE id = ?1", params![id])?;
+            transaction.commit()
+        }).await;
+        if let Err(err) = result {
+            error!("Failed to dead-letter rejected vote in cache database: {}", err);
+        }
+    }
+
+    async fn dead_letters(&self, offset: usize, limit: usize) -> Vec<DeadLetter> {
+        let result = self.run(move |connection| {
+            let mut statement = connection.prepare(
+                "SELECT id, vote, enqueued_at, attempts, last_error, 0, history, reason, dead_at FROM dead_letters
+                 ORDER BY id LIMIT ?1 OFFSET ?2",
+            )?;
+            let dead_letters = statement.query_map(params![limit as i64, offset as i64], read_dead_letter)?
+                .collect::<rusqlite::Result<Vec<DeadLetter>>>();
+            dead_letters
+        }).await;
+        return result.unwrap_or_else(|err| {
+            error!("Failed to list dead letters in cache database: {}", err);
+            Vec::new()
+        });
+    }
+
+    async fn dead_letter_count(&self) -> usize {
+        return self.run(|connection| {
+            connection.query_row("SELECT COUNT(*) FROM dead_letters", [], |row| row.get::<_, i64>(0))
+        }).await
+            .unwrap_or_else(|err| {
+                error!("Failed to count dead letters in cache database: {}", err);
+                0
+            }) as usize;
+    }
+
+    async fn requeue_dead_letter(&mut self, id: u64) -> bool {
+        let result = self.run(move |connection| {
+            let transaction = connection.transaction()?;
+            let now = now_millis() as i64;
+            let moved = transaction.execute(
+                "INSERT INTO votes (id, source, bot, user, vote, enqueued_at, attempts, last_error, next_attempt_at,
+                                    history)
+                 SELECT id, source, bot, user, vote, ?2, 0, last_error, ?2, history FROM dead_letters WHERE id = ?1",
+                params![id as i64, now],
+            )?;
+            transaction.execute("DELETE FROM dead_letters WHERE id = ?1", params![id as i64])?;
+            transaction.commit()?;
+            Ok(moved > 0)
+        }).await;
+        return result.unwrap_or_else(|err| {
+            error!("Failed to requeue dead letter {} in cache database: {}", id, err);
+            false
+        });
+    }
+
+    async fn purge_dead_letters(&mut self, id: Option<u64>) -> usize {
+        let result = self.run(move |connection| match id {
+            Some(id) => connection.execute("DELETE FROM dead_letters WHERE id = ?1", params![id as i64]),
+            None => connection.execute("DELETE FROM dead_letters", []),
+        }).await;
+        return result.unwrap_or_else(|err| {
+            error!("Failed to purge dead letters in cache database: {}", err);
+            0
+        });
+    }
+
+    async fn queued(&self, filter: &QueueFilter, offset: usize, limit: usize) -> (usize, Vec<CachedVote>) {
+        // guilds have no column of their own, they are only part of the vote
+        let condition = "(?1 IS NULL OR source = ?1) AND (?2 IS NULL OR bot = ?2) AND (?3 IS NULL OR user = ?3)
+                         AND (?4 IS NULL OR json_extract(vote, '$.guild') = ?4)";
+        let source = filter.source.clone();
+        let bot = filter.bot.map(|bot| bot.to_string());
+        let user = filter.user.map(|user| user.to_string());
+        let guild = filter.guild.map(|guild| guild.to_string());
+        let result = self.run(move |connection| {
+            let total = connection.query_row(
+                format!("SELECT COUNT(*) FROM votes WHERE {}", condition).as_str(),
+                params![source, bot, user, guild],
+                |row| row.get::<_, i64>(0),
+            )?;
+            let mut statement = connection.prepare(
+                format!("SELECT {} FROM votes WHERE {} ORDER BY id LIMIT ?5 OFFSET ?6", VOTE_COLUMNS, condition)
+                    .as_str())?;
+            let votes = statement.query_map(params![source, bot, user, guild, limit as i64, offset as i64],
+                                            read_cached_vote)?
+                .collect::<rusqlite::Result<Vec<CachedVote>>>()?;
+            Ok((total as usize, votes))
+        }).await;
+        return result.unwrap_or_else(|err| {
+            error!("Failed to list votes in cache database: {}", err);
+            (0, Vec::new())
+        });
+    }
+
+    async fn queued_vote(&self, id: u64) -> Option<CachedVote> {
+        return self.run(move |connection| connection.query_row(
+            format!("SELECT {} FROM votes WHERE id = ?1", VOTE_COLUMNS).as_str(),
+            params![id as i64],
+            read_cached_vote,
+        ).optional()).await.unwrap_or_else(|err| {
+            error!("Failed to read vote {} from cache database: {}", id, err);
+            None
+        });
+    }
+
+    async fn remove_queued(&mut self, id: u64) -> bool {
+        return self.run(move |connection| connection.execute("DELETE FROM votes WHERE id = ?1", params![id as i64]))
+            .await
+            .map(|deleted| deleted > 0)
+            .unwrap_or_else(|err| {
+                error!("Failed to delete vote {} from cache database: {}", id, err);
+                false
+            });
+    }
+
+    async fn expedite_queued(&mut self) -> usize {
+        return self.run(|connection| {
+            connection.execute("UPDATE votes SET next_attempt_at = ?1 WHERE claimed_at IS NULL",
+                               params![now_millis() as i64])
+        }).await
+            .unwrap_or_else(|err| {
+                error!("Failed to expedite votes in cache database: {}", err);
+                0
+            });
+    }
+}
+
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+    return Ok(());
+}
+
+fn insert_vote(connection: &Connection, vote: &CachedVote) -> rusqlite::Result<usize> {
+    return connection.execute(
+        "INSERT INTO votes (source, bot, user, vote, enqueued_at, attempts, last_error, next_attempt_at, history)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![vote.vote.src, vote.vote.bot.0.to_string(), vote.vote.user.0.to_string(),
+                serde_json::to_string(&vote.vote).unwrap(), vote.enqueued_at as i64, vote.attempts,
+                vote.last_error, vote.next_attempt_at as i64, serde_json::to_string(&vote.history).unwrap()],
+    );
+}
+
+fn read_cached_vote(row: &Row) -> rusqlite::Result<CachedVote> {
+    let vote: String = row.get(1)?;
+    return Ok(CachedVote {
+        id: row.get::<_, i64>(0)? as u64,
+        vote: serde_json::from_str(vote.as_str())
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(err)))?,
+        enqueued_at: row.get::<_, i64>(2)? as u64,
+        attempts: row.get(3)?,
+        last_error: row.get(4)?,
+        next_attempt_at: row.get::<_, i64>(5)? as u64,
+        history: serde_json::from_str(row.get::<_, String>(6)?.as_str())
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Text, Box::new(err)))?,
+    });
+}
+
+fn read_dead_letter(row: &Row) -> rusqlite::Result<DeadLetter> {
+    return Ok(DeadLetter {
+        vote: read_cached_vote(row)?,
+        reason: row.get(7)?,
+        dead_at: row.get::<_, i64>(8)? as u64,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /**
+    A fresh directory below the system temp dir, removed again when dropped
+    */
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            return TestDir(std::env::temp_dir().join(format!("vote-sqlite-test-{:016x}", rand::random::<u64>())));
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn vote(user: u64) -> VoteRequest {
+        let vote = format!(r#"{{"bot":"1","user":"{}","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}}"#, user);
+        return serde_json::from_str(vote.as_str()).unwrap();
+    }
+
+    async fn queued_users(cache: &SqliteVoteCache) -> Vec<u64> {
+        let (_, votes) = cache.queued(&QueueFilter::default(), 0, 100).await;
+        return votes.iter().map(|vote| vote.vote.user.0).collect();
+    }
+
+    async fn dead_users(cache: &SqliteVoteCache) -> Vec<u64> {
+        return cache.dead_letters(0, 100).await.iter().map(|letter| letter.vote.vote.user.0).collect();
+    }
+
+    #[tokio::test]
+    async fn replays_database_after_restart() {
+        let dir = TestDir::new();
+        {
+            let mut cache = SqliteVoteCache::open(&dir.0).unwrap();
+            for user in 1..=4 {
+                cache.cache_failed_vote(vote(user), "failed".to_owned(), 0).await;
+            }
+            let delivered = cache.poll().await.unwrap();
+            cache.ack(&delivered).await;
+            let mut retried = cache.poll().await.unwrap();
+            retried.record_failure("failed again".to_owned());
+            cache.return_failed_retry(retried).await;
+            // still in flight when the cache is closed, so it is delivered again after the restart
+            assert_eq!(cache.poll().await.unwrap().id, 2);
+        }
+
+        let mut cache = SqliteVoteCache::open(&dir.0).unwrap();
+        assert_eq!(queued_users(&cache).await, vec![2, 3, 4]);
+        assert_eq!(cache.size().await, 3);
+        assert_eq!(cache.queued_vote(2).await.unwrap().attempts, 2);
+        assert_eq!(cache.poll().await.unwrap().id, 2);
+        assert_eq!(cache.check_ready().await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn retries_vote_once_due() {
+        let dir = TestDir::new();
+        let mut cache = SqliteVoteCache::open(&dir.0).unwrap();
+        cache.cache_failed_vote(vote(1), "failed".to_owned(), now_millis() + 60_000).await;
+        assert!(cache.poll().await.is_none());
+
+        assert_eq!(cache.expedite_queued().await, 1);
+        assert_eq!(cache.poll().await.unwrap().vote.user.0, 1);
+        assert!(cache.poll().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn removes_queued_vote() {
+        let dir = TestDir::new();
+        let mut cache = SqliteVoteCache::open(&dir.0).unwrap();
+        for user in 1..=3 {
+            cache.cache_failed_vote(vote(user), "failed".to_owned(), 0).await;
+        }
+
+        assert!(cache.remove_queued(2).await);
+        assert!(!cache.remove_queued(2).await);
+        assert_eq!(queued_users(&cache).await, vec![1, 3]);
+        let filter = QueueFilter { user: Some(3), ..QueueFilter::default() };
+        let (total, votes) = cache.queued(&filter, 0, 100).await;
+        assert_eq!(total, 1);
+        assert_eq!(votes[0].id, 3);
+        assert!(cache.queued_vote(2).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn filters_queued_votes_by_guild() {
+        let dir = TestDir::new();
+        let mut cache = SqliteVoteCache::open(&dir.0).unwrap();
+        cache.cache_failed_vote(vote(1), "failed".to_owned(), 0).await;
+        let guild_vote = r#"{"bot":"0","guild":"9","kind":"guild","user":"2","type":"upvote","isWeekend":false,
+                             "query":null,"src":"topgg"}"#;
+        cache.cache_failed_vote(serde_json::from_str(guild_vote).unwrap(), "failed".to_owned(), 0).await;
+
+        let filter = QueueFilter { guild: Some(9), ..QueueFilter::default() };
+        let (total, votes) = cache.queued(&filter, 0, 100).await;
+        assert_eq!(total, 1);
+        assert_eq!(votes[0].vote.user.0, 2);
+        let filter = QueueFilter { bot: Some(1), ..QueueFilter::default() };
+        assert_eq!(cache.queued(&filter, 0, 100).await.1[0].vote.user.0, 1);
+    }
+
+    #[tokio::test]
+    async fn requeues_and_purges_dead_letters() {
+        let dir = TestDir::new();
+        let mut cache = SqliteVoteCache::open(&dir.0).unwrap();
+        cache.cache_failed_vote(vote(1), "failed".to_owned(), 0).await;
+        let exhausted = cache.poll().await.unwrap();
+        cache.dead_letter(exhausted, "exhausted".to_owned()).await;
+        cache.dead_letter_failed_vote(vote(2), "rejected".to_owned()).await;
+        cache.dead_letter_failed_vote(vote(3), "rejected".to_owned()).await;
+
+        assert_eq!(cache.size().await, 0);
+        assert_eq!(cache.dead_letter_count().await, 3);
+        assert_eq!(dead_users(&cache).await, vec![1, 2, 3]);
+        assert_eq!(cache.dead_letters(0, 100).await[1].reason, "rejected");
+
+        assert!(cache.requeue_dead_letter(1).await);
+        assert!(!cache.requeue_dead_letter(1).await);
+        let requeued = cache.poll().await.unwrap();
+        assert_eq!((requeued.id, requeued.attempts), (1, 0));
+        assert_eq!(dead_users(&cache).await, vec![2, 3]);
+
+        assert_eq!(cache.purge_dead_letters(Some(2)).await, 1);
+        assert_eq!(cache.purge_dead_letters(Some(2)).await, 0);
+        assert_eq!(dead_users(&cache).await, vec![3]);
+        assert_eq!(cache.purge_dead_letters(None).await, 1);
+        assert_eq!(cache.dead_letter_count().await, 0);
+    }
+}
@@ -1,31 +1,363 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use async_trait::async_trait;
+use log::info;
+use serde::{Serialize, Deserialize};
+use crate::config;
+use crate::constants::{CACHE_BACKEND_MEMORY, CACHE_BACKEND_WAL, CACHE_BACKEND_SQLITE, CACHE_BACKEND_REDIS, DEFAULT_ROUTE,
+                       PRIMARY_SINK};
+use crate::redis_vote_cache::RedisVoteCache;
+use crate::sqlite_vote_cache::SqliteVoteCache;
 use crate::vote_request::VoteRequest;
+use crate::wal_vote_cache::WalVoteCache;
+
+/**
+Amount of failed attempts kept in the history of a vote, older ones are dropped
+*/
+const MAX_ATTEMPT_HISTORY: usize = 20;
+
+/**
+A vote waiting in the cache together with its delivery state
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedVote {
+    pub id: u64,
+    pub vote: VoteRequest,
+    pub enqueued_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub next_attempt_at: u64,
+    #[serde(default)]
+    pub history: Vec<FailedAttempt>,
+}
+
+/**
+A single failed delivery attempt of a cached vote
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedAttempt {
+    pub at: u64,
+    pub error: String,
+}
+
+impl CachedVote {
+    /**
+    A vote which just failed its first delivery attempt
+    */
+    pub fn first_failure(id: u64, vote: VoteRequest, error: String, next_attempt_at: u64) -> CachedVote {
+        let now = now_millis();
+        return CachedVote {
+            id,
+            vote,
+            enqueued_at: now,
+            attempts: 1,
+            last_error: Some(error.clone()),
+            next_attempt_at,
+            history: vec![FailedAttempt { at: now, error }],
+        };
+    }
+
+    /**
+    Counts another failed attempt and adds it to the history
+    */
+    pub fn record_failure(&mut self, error: String) {
+        self.attempts += 1;
+        self.last_error = Some(error.clone());
+        if self.history.len() >= MAX_ATTEMPT_HISTORY {
+            self.history.remove(0);
+        }
+        self.history.push(FailedAttempt { at: now_millis(), error });
+    }
+}
+
+/**
+Narrows down the queued votes listed on the admin endpoints. Server votes are selected by their
+guild, as their bot is always 0
+*/
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct QueueFilter {
+    pub source: Option<String>,
+    pub bot: Option<u64>,
+    pub guild: Option<u64>,
+    pub user: Option<u64>,
+}
+
+impl QueueFilter {
+    pub fn matches(&self, vote: &CachedVote) -> bool {
+        return self.source.as_ref().is_none_or(|source| vote.vote.src.as_ref() == Some(source))
+            && self.bot.is_none_or(|bot| vote.vote.bot.0 == bot)
+            && self.guild.is_none_or(|guild| vote.vote.guild.map(|voted| voted.0) == Some(guild))
+            && self.user.is_none_or(|user| vote.vote.user.0 == user);
+    }
+}
+
+/**
+A vote which exhausted its retry budget and is kept aside until requeued or purged
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    #[serde(flatten)]
+    pub vote: CachedVote,
+    pub reason: String,
+    pub dead_at: u64,
+}
+
+impl DeadLetter {
+    /**
+    Turns the dead letter back into a vote which is due immediately with a fresh retry budget
+    */
+    pub fn into_requeued(self) -> CachedVote {
+        let now = now_millis();
+        return CachedVote {
+            enqueued_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            ..self.vote
+        };
+    }
+}
+
+/**
+Storage for votes which could not be forwarded to the vote endpoint yet.
+
+Polling only yields votes whose `next_attempt_at` has passed. A polled vote stays
+claimed until it is either acknowledged after a successful delivery or handed back
+through `return_failed_retry`.
+*/
+#[async_trait]
+pub trait VoteCache: Send + Sync {
+    async fn cache_failed_vote(&mut self, vote: VoteRequest, error: String, next_attempt_at: u64);
+
+    async fn return_failed_retry(&mut self, vote: CachedVote);
+
+    async fn poll(&mut self) -> Option<CachedVote>;
+
+    async fn ack(&mut self, vote: &CachedVote);
+
+    async fn size(&self) -> usize;
+
+    /**
+    Checks the storage is usable, returns the reason if it isn't
+    */
+    async fn check_ready(&self) -> Result<(), String>;
+
+    /**
+    Time the longest waiting vote was enqueued at, including claimed ones
+    */
+    async fn oldest_enqueued_at(&self) -> Option<u64>;
+
+    /**
+    Moves a polled vote into the dead-letter store
+    */
+    async fn dead_letter(&mut self, vote: CachedVote, reason: String);
+
+    /**
+    Stores a vote which the vote endpoint rejected straight away as a dead letter
+    */
+    async fn dead_letter_failed_vote(&mut self, vote: VoteRequest, reason: String);
+
+    async fn dead_letters(&self, offset: usize, limit: usize) -> Vec<DeadLetter>;
+
+    async fn dead_letter_count(&self) -> usize;
+
+    /**
+    Moves a dead letter back into the queue, returns false if there is none with the given id
+    */
+    async fn requeue_dead_letter(&mut self, id: u64) -> bool;
+
+    /**
+    Deletes the dead letter with the given id or all dead letters, returns the amount deleted
+    */
+    async fn purge_dead_letters(&mut self, id: Option<u64>) -> usize;
+
+    /**
+    Queued votes matching the filter ordered by id, together with the total amount matching
+    */
+    async fn queued(&self, filter: &QueueFilter, offset: usize, limit: usize) -> (usize, Vec<CachedVote>);
+
+    async fn queued_vote(&self, id: u64) -> Option<CachedVote>;
+
+    /**
+    Deletes a queued vote without delivering it, returns false if there is none with the given id
+    */
+    async fn remove_queued(&mut self, id: u64) -> bool;
+
+    /**
+    Makes every queued vote due immediately, returns the amount of votes affected
+    */
+    async fn expedite_queued(&mut self) -> usize;
+}
+
+/**
+Applies filter and paging to votes of the in-memory backends
+*/
+pub fn page_queued<'a>(votes: impl Iterator<Item = &'a CachedVote>, filter: &QueueFilter, offset: usize,
+                       limit: usize) -> (usize, Vec<CachedVote>) {
+    let mut matching: Vec<&CachedVote> = votes.filter(|vote| filter.matches(vote)).collect();
+    matching.sort_by_key(|vote| vote.id);
+    let page = matching.iter().skip(offset).take(limit).map(|vote| (*vote).clone()).collect();
+    return (matching.len(), page);
+}
+
+/**
+Creates the cache backend selected by VOTE_CACHE_BACKEND for a sink of a route, replaying persisted votes if any.
+The endpoint of the default route keeps its votes in `{cache_path}` or below `{prefix}`, the endpoints of other
+routes in `{cache_path}-{route}` or below `{prefix}:route:{route}` and further sinks in `{cache_path}-{route}.{sink}`
+or below `{prefix}:route:{route}:sink:{sink}`
+*/
+pub async fn create_vote_cache(route: &str, sink: &str) -> Box<dyn VoteCache> {
+    let config = config::get();
+    let (mut path, mut redis_prefix) = if route == DEFAULT_ROUTE {
+        (config.cache_path.clone(), config.cache_redis_prefix.clone())
+    } else {
+        (format!("{}-{}", config.cache_path, route), format!("{}:route:{}", config.cache_redis_prefix, route))
+    };
+    if sink != PRIMARY_SINK {
+        path = format!("{}-{}.{}", config.cache_path, route, sink);
+        redis_prefix = format!("{}:route:{}:sink:{}", config.cache_redis_prefix, route, sink);
+    }
+    let path = Path::new(path.as_str());
+    let cache: Box<dyn VoteCache> = match config.cache_backend.as_str() {
+        CACHE_BACKEND_MEMORY => Box::new(MemoryVoteCache::new()),
+        CACHE_BACKEND_WAL => Box::new(WalVoteCache::open(path, config.cache_segment_size)
+            .expect("Failed to open vote cache log")),
+        CACHE_BACKEND_SQLITE => Box::new(SqliteVoteCache::open(path)
+            .expect("Failed to open vote cache database")),
+        CACHE_BACKEND_REDIS => Box::new(RedisVoteCache::connect(config.cache_redis_url.as_str(),
+                                                                redis_prefix.as_str(),
+                                                                config.cache_redis_lease().as_millis() as u64).await
+            .expect("Failed to connect to vote cache redis")),
+        backend => panic!("Unknown vote cache backend: {}", backend),
+    };
+    info!("Using {} vote cache for {}/{} with {} cached votes", config.cache_backend, route, sink, cache.size().await);
+    return cache;
+}
+
+/**
+Milliseconds since the unix epoch, used for all timestamps stored in the cache
+*/
+pub fn now_millis() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| { duration.as_millis() as u64 })
+        .unwrap_or(0);
+}
+
+/**
+Index of the first vote in the queue which is due for its next attempt
+*/
+pub fn next_eligible(queue: &VecDeque<CachedVote>) -> Option<usize> {
+    let now = now_millis();
+    return queue.iter().position(|vote| vote.next_attempt_at <= now);
+}
 
 #[derive(Clone)]
-pub struct VoteCache {
-    cache: VecDeque<VoteRequest>
+pub struct MemoryVoteCache {
+    cache: VecDeque<CachedVote>,
+    dead: BTreeMap<u64, DeadLetter>,
+    next_id: u64,
 }
 
-impl VoteCache {
-    pub fn new() -> VoteCache {
-        return VoteCache {
-            cache: VecDeque::new()
+impl MemoryVoteCache {
+    pub fn new() -> MemoryVoteCache {
+        return MemoryVoteCache {
+            cache: VecDeque::new(),
+            dead: BTreeMap::new(),
+            next_id: 0,
         };
     }
+}
 
-    pub fn cache_failed_vote(&mut self, vote: VoteRequest) {
-        self.cache.push_back(vote);
+#[async_trait]
+impl VoteCache for MemoryVoteCache {
+    async fn cache_failed_vote(&mut self, vote: VoteRequest, error: String, next_attempt_at: u64) {
+        self.cache.push_back(CachedVote::first_failure(self.next_id, vote, error, next_attempt_at));
+        self.next_id += 1;
     }
 
-    pub fn return_failed_retry(&mut self, vote: VoteRequest) {
+    async fn return_failed_retry(&mut self, vote: CachedVote) {
         self.cache.push_front(vote);
     }
 
-    pub fn poll(&mut self) -> Option<VoteRequest> {
-        return self.cache.pop_front();
+    async fn poll(&mut self) -> Option<CachedVote> {
+        let index = next_eligible(&self.cache)?;
+        return self.cache.remove(index);
     }
 
-    pub fn size(&self) -> usize {
+    async fn ack(&mut self, _vote: &CachedVote) {}
+
+    async fn size(&self) -> usize {
         self.cache.len()
     }
-}
\ No newline at end of file
+
+    async fn check_ready(&self) -> Result<(), String> {
+        return Ok(());
+    }
+
+    async fn oldest_enqueued_at(&self) -> Option<u64> {
+        return self.cache.iter().map(|vote| vote.enqueued_at).min();
+    }
+
+    async fn dead_letter(&mut self, vote: CachedVote, reason: String) {
+        self.dead.insert(vote.id, DeadLetter { vote, reason, dead_at: now_millis() });
+    }
+
+    async fn dead_letter_failed_vote(&mut self, vote: VoteRequest, reason: String) {
+        let vote = CachedVote::first_failure(self.next_id, vote, reason.clone(), now_millis());
+        self.next_id += 1;
+        self.dead.insert(vote.id, DeadLetter { dead_at: vote.enqueued_at, vote, reason });
+    }
+
+    async fn dead_letters(&self, offset: usize, limit: usize) -> Vec<DeadLetter> {
+        return self.dead.values().skip(offset).take(limit).cloned().collect();
+    }
+
+    async fn dead_letter_count(&self) -> usize {
+        self.dead.len()
+    }
+
+    async fn requeue_dead_letter(&mut self, id: u64) -> bool {
+        return match self.dead.remove(&id) {
+            Some(letter) => {
+                self.cache.push_back(letter.into_requeued());
+                true
+            }
+            None => false,
+        };
+    }
+
+    async fn purge_dead_letters(&mut self, id: Option<u64>) -> usize {
+        return match id {
+            Some(id) => self.dead.remove(&id).map_or(0, |_| 1),
+            None => {
+                let count = self.dead.len();
+                self.dead.clear();
+                count
+            }
+        };
+    }
+
+    async fn queued(&self, filter: &QueueFilter, offset: usize, limit: usize) -> (usize, Vec<CachedVote>) {
+        return page_queued(self.cache.iter(), filter, offset, limit);
+    }
+
+    async fn queued_vote(&self, id: u64) -> Option<CachedVote> {
+        return self.cache.iter().find(|vote| vote.id == id).cloned();
+    }
+
+    async fn remove_queued(&mut self, id: u64) -> bool {
+        let size = self.cache.len();
+        self.cache.retain(|vote| vote.id != id);
+        return self.cache.len() < size;
+    }
+
+    async fn expedite_queued(&mut self) -> usize {
+        let now = now_millis();
+        self.cache.iter_mut().for_each(|vote| vote.next_attempt_at = now);
+        return self.cache.len();
+    }
+}
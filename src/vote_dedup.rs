@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use log::{error, info};
+use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, RedisResult};
+use sha2::{Digest, Sha256};
+use crate::config;
+use crate::constants::CACHE_BACKEND_REDIS;
+use crate::vote_cache::now_millis;
+use crate::vote_request::{Vote, VoteRequest};
+
+/**
+Key under which a vote is remembered to recognise its re-deliveries, the key the request brought
+or else derived from what was voted for by whom. It has no time component, the store forgets it
+VOTE_DEDUP_WINDOW after it was recorded
+*/
+pub fn dedup_key(vote: &VoteRequest) -> String {
+    if let Some(key) = vote.idempotency_key.as_ref() {
+        return key.clone();
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}:{}:{}:{}", vote.get_source(), vote.target().0, vote.user.0, vote.r#type));
+    return format!("{:x}", hasher.finalize());
+}
+
+/**
+Deterministic key of a vote received at `received_at` passed on to the sinks, equal for every
+re-delivery of the same vote by a bot list within the same VOTE_DEDUP_WINDOW bucket so replicas
+which don't share their dedup store mostly pass on the same key
+*/
+pub fn idempotency_key(vote: &VoteRequest, received_at: u64) -> String {
+    let window = config::get().dedup_window().as_millis() as u64;
+    let bucket = received_at.checked_div(window).unwrap_or(received_at);
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}:{}:{}:{}:{}", vote.get_source(), vote.target().0, vote.user.0, vote.r#type, bucket));
+    return format!("{:x}", hasher.finalize());
+}
+
+/**
+Remembers idempotency keys of accepted votes for VOTE_DEDUP_WINDOW
+*/
+#[async_trait]
+pub trait DedupStore: Send + Sync {
+    /**
+    Records the key, returns false if it was already recorded within the window
+    */
+    async fn first_seen(&self, key: &str) -> bool;
+
+    /**
+    Removes the key again, for votes which were recorded but could not be accepted
+    */
+    async fn forget(&self, key: &str);
+}
+
+/**
+Outcome of passing on a vote at most once
+*/
+#[derive(Debug, PartialEq)]
+pub enum Forwarded {
+    Accepted,
+    Duplicate,
+    Failed,
+}
+
+/**
+Passes on the vote with `forward` unless its key was seen within the window. The key is forgotten
+again if that failed, so the retry of the bot list isn't taken for a duplicate
+*/
+pub async fn forward_once<F, Fut>(dedup: &dyn DedupStore, key: &str, forward: F) -> Forwarded
+    where F: FnOnce() -> Fut, Fut: Future<Output = bool> {
+    if !dedup.first_seen(key).await {
+        return Forwarded::Duplicate;
+    }
+    if forward().await {
+        return Forwarded::Accepted;
+    }
+    dedup.forget(key).await;
+    return Forwarded::Failed;
+}
+
+/**
+Creates the dedup store matching the vote cache, shared between replicas when using redis
+*/
+pub async fn create_dedup_store() -> Arc<dyn DedupStore> {
+    let config = config::get();
+    let window_millis = config.dedup_window().as_millis() as u64;
+    if window_millis == 0 {
+        info!("Vote deduplication disabled");
+        return Arc::new(DisabledDedupStore {});
+    }
+    if config.cache_backend.as_str() == CACHE_BACKEND_REDIS {
+        return Arc::new(RedisDedupStore::connect(config.cache_redis_url.as_str(), config.cache_redis_prefix.as_str(),
+                                                 window_millis).await
+            .expect("Failed to connect to vote dedup redis"));
+    }
+    return Arc::new(MemoryDedupStore::new(window_millis));
+}
+
+pub struct DisabledDedupStore {}
+
+#[async_trait]
+impl DedupStore for DisabledDedupStore {
+    async fn first_seen(&self, _key: &str) -> bool {
+        true
+    }
+
+    async fn forget(&self, _key: &str) {}
+}
+
+pub struct MemoryDedupStore {
+    seen: Mutex<HashMap<String, u64>>,
+    window_millis: u64,
+}
+
+impl MemoryDedupStore {
+    pub fn new(window_millis: u64) -> MemoryDedupStore {
+        return MemoryDedupStore {
+            seen: Mutex::new(HashMap::new()),
+            window_millis,
+        };
+    }
+}
+
+#[async_trait]
+impl DedupStore for MemoryDedupStore {
+    async fn first_seen(&self, key: &str) -> bool {
+        let now = now_millis();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expires_at| *expires_at > now);
+        if seen.contains_key(key) {
+            return false;
+        }
+        seen.insert(key.to_owned(), now + self.window_millis);
+        return true;
+    }
+
+    async fn forget(&self, key: &str) {
+        self.seen.lock().unwrap().remove(key);
+    }
+}
+
+/**
+Dedup store shared by all replicas, each key is a redis key expiring after the window
+*/
+pub struct RedisDedupStore {
+    connection: ConnectionManager,
+    prefix: String,
+    window_millis: u64,
+}
+
+impl RedisDedupStore {
+    pub async fn connect(url: &str, prefix: &str, window_millis: u64) -> RedisResult<RedisDedupStore> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client).await?;
+        return Ok(RedisDedupStore {
+            connection,
+            prefix: format!("{}:dedup", prefix),
+            window_millis,
+        });
+    }
+
+    async fn try_first_seen(&self, key: &str) -> RedisResult<bool> {
+        let mut connection = self.connection.clone();
+        let options = redis::SetOptions::default()
+            .conditional_set(redis::ExistenceCheck::NX)
+            .with_expiration(redis::SetExpiry::PX(self.window_millis as usize));
+        let result: Option<String> = connection.set_options(self.redis_key(key), 1, options).await?;
+        return Ok(result.is_some());
+    }
+
+    fn redis_key(&self, key: &str) -> String {
+        return format!("{}:{}", self.prefix, key);
+    }
+}
+
+#[async_trait]
+impl DedupStore for RedisDedupStore {
+    async fn first_seen(&self, key: &str) -> bool {
+        // Rather forward a duplicate than lose a vote while redis is unavailable
+        return self.try_first_seen(key).await.unwrap_or_else(|err| {
+            error!("Failed to check vote idempotency key in redis: {}", err);
+            true
+        });
+    }
+
+    async fn forget(&self, key: &str) {
+        let mut connection = self.connection.clone();
+        let result: RedisResult<()> = connection.del(self.redis_key(key)).await;
+        if let Err(err) = result {
+            error!("Failed to remove vote idempotency key from redis: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn vote(user: u64) -> VoteRequest {
+        let vote = format!(r#"{{"bot":"1","user":"{}","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}}"#, user);
+        return serde_json::from_str(vote.as_str()).unwrap();
+    }
+
+    #[test]
+    fn dedup_key_ignores_time_buckets() {
+        let window = config::get().dedup_window().as_millis() as u64;
+        let before = window - 1;
+        let after = window + 1;
+
+        assert_ne!(idempotency_key(&vote(7), before), idempotency_key(&vote(7), after));
+        assert_eq!(dedup_key(&vote(7)), dedup_key(&vote(7)));
+        assert_ne!(dedup_key(&vote(7)), dedup_key(&vote(8)));
+    }
+
+    #[test]
+    fn dedup_key_prefers_key_of_request() {
+        let mut vote = vote(7);
+        vote.idempotency_key = Some("from-client".to_owned());
+
+        assert_eq!(dedup_key(&vote), "from-client");
+    }
+
+    #[tokio::test]
+    async fn recognises_redelivery_within_window() {
+        let dedup = MemoryDedupStore::new(60_000);
+        let key = dedup_key(&vote(7));
+
+        assert!(dedup.first_seen(key.as_str()).await);
+        assert!(!dedup.first_seen(key.as_str()).await);
+        assert!(dedup.first_seen(dedup_key(&vote(8)).as_str()).await);
+    }
+
+    #[tokio::test]
+    async fn forgets_key_after_window() {
+        let dedup = MemoryDedupStore::new(20);
+        let key = dedup_key(&vote(7));
+
+        assert!(dedup.first_seen(key.as_str()).await);
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(dedup.first_seen(key.as_str()).await);
+    }
+
+    #[tokio::test]
+    async fn failed_forward_is_not_a_duplicate() {
+        let dedup = MemoryDedupStore::new(60_000);
+
+        assert_eq!(forward_once(&dedup, "key", || async { false }).await, Forwarded::Failed);
+        assert_eq!(forward_once(&dedup, "key", || async { true }).await, Forwarded::Accepted);
+        assert_eq!(forward_once(&dedup, "key", || async { true }).await, Forwarded::Duplicate);
+    }
+
+    #[tokio::test]
+    async fn duplicate_is_not_forwarded() {
+        let dedup = MemoryDedupStore::new(60_000);
+        forward_once(&dedup, "key", || async { true }).await;
+
+        let forwarded = forward_once(&dedup, "key", || async { panic!("forwarded a duplicate") }).await;
+        assert_eq!(forwarded, Forwarded::Duplicate);
+    }
+}
@@ -1,96 +1,794 @@
-use crate::constants::{VOTE_ENDPOINT, VOTE_ENDPOINT_AUTH_TOKEN, VOTE_RESEND_BULK_COUNT};
-use crate::vote_cache::VoteCache;
+use crate::cache_task::CacheTask;
+use crate::config;
+use crate::constants::{DEFAULT_ROUTE, PRIMARY_SINK, CACHE_TASK_OP_RESEND, CACHE_TASK_OP_LIST_DEAD_LETTERS,
+                       CACHE_TASK_OP_REQUEUE_DEAD_LETTER, CACHE_TASK_OP_PURGE_DEAD_LETTERS,
+                       CACHE_TASK_OP_LIST_QUEUE, CACHE_TASK_OP_GET_QUEUED, CACHE_TASK_OP_DELETE_QUEUED,
+                       CACHE_TASK_OP_FORCE_RESEND, CACHE_TASK_OP_VOTE, CACHE_TASK_OP_PAUSE_RESEND,
+                       CACHE_TASK_OP_RESUME_RESEND};
+use crate::retry_policy::{next_attempt_at, exhausted_reason};
+use crate::forward_result::ForwardResult;
+use crate::vote_cache::{VoteCache, CachedVote, now_millis};
+use crate::vote_sink::VoteSink;
+use crate::metrics;
 use crate::vote_request::VoteRequest;
-use serde::{Serialize, Deserialize};
-use reqwest::Client;
+use futures::future::join_all;
 use log::{info, debug, warn, error};
+use serde_json::{json, Value};
+use core::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::SystemTime;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{oneshot, Mutex};
 
-#[derive(Clone)]
+/**
+Votes waiting for their first delivery to a sink in memory, further votes for a sink which is behind
+are written to its cache right away so they survive a restart and don't grow memory
+*/
+pub const QUEUE_CAPACITY: usize = 100;
+
+/**
+The cache of a sink, shared by its queue task and the votes spilled into it
+*/
+type SharedCache = Arc<Mutex<Box<dyn VoteCache>>>;
+
+/**
+Fans votes and admin tasks out to the sink queues, which each run on their own task
+*/
 pub struct VoteHandler {
-    cache: VoteCache,
-    http_client: Client,
+    queues: Vec<QueueHandle>,
+    paused: Arc<AtomicBool>,
+}
+
+/**
+The channels to the task of a sink queue. Offering a vote never waits, so a sink that is behind
+never holds up the processing loop and with it the other sinks. Admin tasks are few and get their
+own channel, of resend tasks at most one is pending
+*/
+#[derive(Clone)]
+struct QueueHandle {
+    route: String,
+    name: String,
+    votes: Sender<VoteRequest>,
+    tx: UnboundedSender<CacheTask>,
+    cache: SharedCache,
+    resend_pending: Arc<AtomicBool>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-struct VoteResponse {
-    status: String
+/**
+Checks the sink queues for the health endpoints. It probes their caches and channels directly, so a
+sink which is busy delivering doesn't hold up the checks
+*/
+#[derive(Clone)]
+pub struct QueueProbe {
+    queues: Vec<QueueHandle>,
+}
+
+/**
+A sink of a route with the votes waiting to be resent to it, every sink is delivered to and
+retried independently of the others
+*/
+pub struct SinkQueue {
+    route: String,
+    name: String,
+    sink: Box<dyn VoteSink>,
+    cache: SharedCache,
+}
+
+/**
+Selects the queue of a sink, the endpoint of the default route if not given
+*/
+#[derive(Debug, Default, Clone)]
+pub struct QueueSelector {
+    pub route: Option<String>,
+    pub sink: Option<String>,
+}
+
+impl QueueSelector {
+    /**
+    Whether the queue is selected, treating missing parts as wildcard
+    */
+    fn includes(&self, route: &str, name: &str) -> bool {
+        return self.route.as_ref().is_none_or(|selected| selected == route)
+            && self.sink.as_ref().is_none_or(|selected| selected == name);
+    }
+
+    fn selects(&self, route: &str, name: &str) -> bool {
+        return self.route.as_deref().unwrap_or(DEFAULT_ROUTE) == route
+            && self.sink.as_deref().unwrap_or(PRIMARY_SINK) == name;
+    }
+}
+
+impl QueueHandle {
+    fn send(&self, task: CacheTask) -> bool {
+        if self.tx.send(task).is_err() {
+            error!("Queue of {}/{} stopped", self.route, self.name);
+            return false;
+        }
+        return true;
+    }
+
+    /**
+    Passes the vote to the queue task, or writes it to the cache to be resent if the task is behind
+    */
+    fn offer(&self, vote: VoteRequest) -> bool {
+        let vote = match self.votes.try_send(vote) {
+            Ok(()) => return true,
+            Err(TrySendError::Full(vote)) => vote,
+            Err(TrySendError::Closed(_)) => {
+                error!("Queue of {}/{} stopped", self.route, self.name);
+                return false;
+            }
+        };
+        warn!("Queue of {}/{} is full, adding vote from {} to cache", self.route, self.name, vote.user.0);
+        let cache = self.cache.clone();
+        let reason = format!("Queue of {}/{} was full", self.route, self.name);
+        tokio::spawn(async move {
+            cache.lock().await.cache_failed_vote(vote, reason, now_millis()).await;
+        });
+        return true;
+    }
 }
 
 impl VoteHandler {
-    pub fn new() -> VoteHandler {
-        return VoteHandler {
-            cache: VoteCache::new(),
-            http_client: reqwest::Client::builder()
-                .http1_title_case_headers()
-                .build()
-                .unwrap(),
+    pub fn new(queues: Vec<SinkQueue>) -> VoteHandler {
+        let paused = Arc::new(AtomicBool::new(false));
+        let queues = queues.into_iter()
+            .map(|queue| queue.spawn(paused.clone()))
+            .collect();
+        return VoteHandler { queues, paused };
+    }
+
+    /**
+    Carries out a task of the processing loop
+    */
+    pub fn process(&self, mut task: CacheTask) {
+        if task.op == CACHE_TASK_OP_VOTE {
+            self.accept_vote_request(task.vote.unwrap());
+        } else if task.op == CACHE_TASK_OP_RESEND {
+            self.resend_votes();
+        } else if task.op == CACHE_TASK_OP_FORCE_RESEND {
+            self.force_resend_votes(task);
+        } else if task.op == CACHE_TASK_OP_PAUSE_RESEND || task.op == CACHE_TASK_OP_RESUME_RESEND {
+            self.set_paused(task.op == CACHE_TASK_OP_PAUSE_RESEND);
+            task.reply(json!({ "paused": self.is_paused() }));
+        } else {
+            self.dispatch(task);
+        }
+    }
+
+    /**
+    Hands the vote to all sinks of its route, each delivers it and caches it if that failed on its own
+    */
+    pub fn accept_vote_request(&self, vote: VoteRequest) {
+        let route = config::get().route_for(vote.target().0).to_owned();
+        let mut accepted = false;
+        for queue in self.queues.iter().filter(|queue| queue.route == route) {
+            accepted |= queue.offer(vote.clone());
+        }
+        if !accepted {
+            error!("No sinks for route {}, dropping vote from {}", route, vote.user.0);
+        }
+    }
+
+    /**
+    Lets every sink resend up to VOTE_RESEND_BULK_COUNT due votes. Rejected votes are moved to the dead
+    letters right away, any other failure pushes the vote back by its backoff delay so it
+    won't block the others and ends the run of the sink as it is likely unavailable.
+    Polled votes are claimed by this instance, so with a shared cache every replica
+    works through a different part of the queue. While paused the sinks only refresh their gauges.
+    */
+    pub fn resend_votes(&self) {
+        if self.is_paused() {
+            debug!("Resending votes is paused");
+        }
+        for queue in self.queues.iter() {
+            // a sink which is behind would otherwise pile up resend tasks
+            if !queue.resend_pending.swap(true, Ordering::Relaxed) {
+                queue.send(CacheTask::create_resend_task());
+            }
+        }
+    }
+
+    /**
+    Passes an admin task to the queue it selects, answering null if there is none
+    */
+    pub fn dispatch(&self, mut task: CacheTask) {
+        match self.queues.iter().find(|queue| task.selector.selects(queue.route.as_str(), queue.name.as_str())) {
+            Some(queue) => {
+                queue.send(task);
+            }
+            None => task.reply(json!(null)),
+        }
+    }
+
+    /**
+    Makes every queued vote of the selected sinks due and resends them right away, even while
+    resending is paused. A missing route or sink selects all of them, answers null if nothing was selected
+    */
+    pub fn force_resend_votes(&self, mut task: CacheTask) {
+        let replies = self.ask(&task.selector, CacheTask::create_force_resend_task);
+        if replies.is_empty() {
+            task.reply(json!(null));
+            return;
+        }
+        tokio::spawn(async move {
+            let expedited: u64 = join_all(replies).await.into_iter()
+                .filter_map(|reply| reply.ok()?["expedited"].as_u64())
+                .sum();
+            task.reply(json!({ "expedited": expedited }));
+        });
+    }
+
+    /**
+    A probe of the sink queues, which stays usable after the handler moved into the processing loop
+    */
+    pub fn probe(&self) -> QueueProbe {
+        return QueueProbe { queues: self.queues.clone() };
+    }
+
+    /**
+    Sends a task to every queue the selector includes, returning the receivers of their replies
+    */
+    fn ask<F>(&self, selector: &QueueSelector, create_task: F) -> Vec<oneshot::Receiver<Value>>
+        where F: Fn(QueueSelector, oneshot::Sender<Value>) -> CacheTask {
+        return self.queues.iter()
+            .filter(|queue| selector.includes(queue.route.as_str(), queue.name.as_str()))
+            .map(|queue| {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let selector = QueueSelector { route: Some(queue.route.clone()), sink: Some(queue.name.clone()) };
+                queue.send(create_task(selector, reply_tx));
+                reply_rx
+            })
+            .collect();
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+        info!("Resending votes {}", if paused { "paused" } else { "resumed" });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        return self.paused.load(Ordering::Relaxed);
+    }
+}
+
+impl QueueProbe {
+    /**
+    Checks the caches of all sinks, failing with every sink whose cache isn't usable or if they
+    didn't all answer within the timeout
+    */
+    pub async fn check_storage(&self, timeout: Duration) -> Result<(), String> {
+        let checks = self.queues.iter().map(|queue| async move {
+            let ready = queue.cache.lock().await.check_ready().await;
+            return ready.map_err(|err| format!("{}/{}: {}", queue.route, queue.name, err));
+        });
+        let results = match tokio::time::timeout(timeout, join_all(checks)).await {
+            Ok(results) => results,
+            Err(_) => return Err("Cache did not respond in time".to_owned()),
+        };
+        let errors: Vec<String> = results.into_iter().filter_map(|result| result.err()).collect();
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+        return Ok(());
+    }
+
+    /**
+    Route and name of every sink whose queue task stopped and no longer takes votes
+    */
+    pub fn stopped(&self) -> Vec<String> {
+        return self.queues.iter()
+            .filter(|queue| queue.votes.is_closed())
+            .map(|queue| format!("{}/{}", queue.route, queue.name))
+            .collect();
+    }
+}
+
+impl SinkQueue {
+    pub fn new(route: String, name: String, sink: Box<dyn VoteSink>, cache: Box<dyn VoteCache>) -> SinkQueue {
+        return SinkQueue { route, name, sink, cache: Arc::new(Mutex::new(cache)) };
+    }
+
+    fn spawn(self, paused: Arc<AtomicBool>) -> QueueHandle {
+        let (votes, votes_rx) = mpsc::channel(QUEUE_CAPACITY);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let resend_pending = Arc::new(AtomicBool::new(false));
+        let handle = QueueHandle {
+            route: self.route.clone(),
+            name: self.name.clone(),
+            votes,
+            tx,
+            cache: self.cache.clone(),
+            resend_pending: resend_pending.clone(),
         };
+        tokio::spawn(self.run(votes_rx, rx, paused, resend_pending));
+        return handle;
+    }
+
+    /**
+    Works through the votes and tasks of this sink one after another, tasks first
+    */
+    async fn run(mut self, mut votes: Receiver<VoteRequest>, mut rx: UnboundedReceiver<CacheTask>,
+                 paused: Arc<AtomicBool>, resend_pending: Arc<AtomicBool>) {
+        debug!("Started queue of {}", self.label());
+        // votes and dead letters may be left over from before a restart
+        self.update_queue_metrics().await;
+        loop {
+            let mut task = tokio::select! {
+                biased;
+                task = rx.recv() => match task {
+                    Some(task) => task,
+                    None => break,
+                },
+                vote = votes.recv() => match vote {
+                    Some(vote) => {
+                        self.accept(vote).await;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            if task.op == CACHE_TASK_OP_RESEND {
+                resend_pending.store(false, Ordering::Relaxed);
+                // resend tasks queued up behind a slow delivery may arrive after a pause
+                if paused.load(Ordering::Relaxed) {
+                    self.update_queue_metrics().await;
+                } else {
+                    self.resend_due_votes().await;
+                }
+            } else if task.op == CACHE_TASK_OP_LIST_QUEUE {
+                let (total, votes) = self.cache.lock().await.queued(&task.filter, task.offset, task.limit).await;
+                task.reply(json!({ "total": total, "paused": paused.load(Ordering::Relaxed), "votes": votes }));
+            } else if task.op == CACHE_TASK_OP_GET_QUEUED {
+                let vote = self.cache.lock().await.queued_vote(task.id.unwrap()).await;
+                task.reply(json!(vote));
+            } else if task.op == CACHE_TASK_OP_DELETE_QUEUED {
+                let id = task.id.unwrap();
+                if self.cache.lock().await.remove_queued(id).await {
+                    info!("Deleted queued vote {} of {}", id, self.label());
+                    self.update_queue_metrics().await;
+                    task.reply(json!({ "deleted": id }));
+                } else {
+                    task.reply(json!(null));
+                }
+            } else if task.op == CACHE_TASK_OP_LIST_DEAD_LETTERS {
+                let total = self.cache.lock().await.dead_letter_count().await;
+                let dead_letters = self.cache.lock().await.dead_letters(task.offset, task.limit).await;
+                task.reply(json!({ "total": total, "deadLetters": dead_letters }));
+            } else if task.op == CACHE_TASK_OP_REQUEUE_DEAD_LETTER {
+                let id = task.id.unwrap();
+                if self.cache.lock().await.requeue_dead_letter(id).await {
+                    info!("Requeued dead letter {} of {}", id, self.label());
+                    self.update_queue_metrics().await;
+                    task.reply(json!({ "requeued": id }));
+                } else {
+                    task.reply(json!(null));
+                }
+            } else if task.op == CACHE_TASK_OP_PURGE_DEAD_LETTERS {
+                let purged = self.cache.lock().await.purge_dead_letters(task.id).await;
+                info!("Purged {} dead letters of {}", purged, self.label());
+                self.update_queue_metrics().await;
+                if task.id.is_none() || purged > 0 {
+                    task.reply(json!({ "purged": purged }));
+                } else {
+                    task.reply(json!(null));
+                }
+            } else if task.op == CACHE_TASK_OP_FORCE_RESEND {
+                let expedited = self.cache.lock().await.expedite_queued().await;
+                info!("Forcing resend of {} votes to {}", expedited, self.label());
+                task.reply(json!({ "expedited": expedited }));
+                self.resend_due_votes().await;
+            }
+        }
+        debug!("Stopped queue of {}", self.label());
+    }
+
+    /**
+    Route and name of the sink for logs
+    */
+    fn label(&self) -> String {
+        return format!("{}/{}", self.route, self.name);
     }
 
-    pub async fn accept_vote_request(&mut self, vote: VoteRequest) {
+    async fn accept(&mut self, vote: VoteRequest) {
         let start = SystemTime::now();
-        if !self.forward_vote(vote.clone()).await {
-            warn!("Adding send-failed vote to cache!");
-            self.cache.cache_failed_vote(vote.clone());
+        let result = self.deliver(&vote).await;
+        let delivered = matches!(result, ForwardResult::Delivered);
+        match result {
+            ForwardResult::Delivered => {}
+            ForwardResult::Rejected { reason } => {
+                warn!("Moving vote rejected by {} to dead letters: {}", self.label(), reason);
+                metrics::DEAD_LETTERED.with_label_values(&["rejected"]).inc();
+                self.cache.lock().await.dead_letter_failed_vote(vote.clone(), reason).await;
+            }
+            ForwardResult::Retryable { reason, retry_after } => {
+                warn!("Adding vote failed to send to {} to cache!", self.label());
+                self.cache.lock().await.cache_failed_vote(vote.clone(), reason, next_attempt_at(1, retry_after)).await;
+            }
+            ForwardResult::Unauthorized { reason } => {
+                error!("{} refused its auth token, adding vote to cache: {}", self.label(), reason);
+                self.cache.lock().await.cache_failed_vote(vote.clone(), reason, next_attempt_at(1, None)).await;
+            }
+        }
+        if !delivered {
+            self.update_queue_metrics().await;
         }
         let elapsed_ms = start.elapsed()
             .map(|duration| { duration.as_millis() })
             .unwrap_or(0);
-        info!("Processed vote request from {} via {} in {}ms", vote.user.0.to_string().as_str(),
-              vote.src.unwrap_or("dbl".to_owned()).as_str(), elapsed_ms);
+        info!("Processed vote request from {} via {} to {} in {}ms", vote.user.0, vote.src.as_deref().unwrap_or("dbl"),
+              self.label(), elapsed_ms);
     }
 
-    pub async fn resend_votes(&mut self) {
-        debug!("Resending votes...");
+    async fn resend_due_votes(&mut self) {
+        debug!("Resending votes to {}...", self.label());
         let start = SystemTime::now();
         let mut count: u32 = 0;
-        while let Some(vote) = self.cache.poll() {
-            if !self.forward_vote(vote.clone()).await {
-                self.cache.return_failed_retry(vote);
-                break;
+        let mut processed: u32 = 0;
+        let bulk_count = config::get().resend_bulk_count;
+        loop {
+            // the cache is only locked around its own calls, never during a delivery
+            let polled = self.cache.lock().await.poll().await;
+            let mut cached = match polled {
+                Some(cached) => cached,
+                None => break,
+            };
+            processed += 1;
+            match self.deliver(&cached.vote).await {
+                ForwardResult::Delivered => {
+                    self.cache.lock().await.ack(&cached).await;
+                    count += 1;
+                }
+                ForwardResult::Rejected { reason } => {
+                    cached.record_failure(reason.clone());
+                    warn!("Moving vote {} rejected by {} to dead letters: {}", cached.id, self.label(), reason);
+                    metrics::DEAD_LETTERED.with_label_values(&["rejected"]).inc();
+                    self.cache.lock().await.dead_letter(cached, reason).await;
+                }
+                ForwardResult::Retryable { reason, retry_after } => {
+                    self.retry_later(cached, reason, retry_after).await;
+                    break;
+                }
+                ForwardResult::Unauthorized { reason } => {
+                    error!("{} refused its auth token: {}", self.label(), reason);
+                    self.retry_later(cached, reason, None).await;
+                    break;
+                }
             }
-            count = count + 1;
-            if count >= *VOTE_RESEND_BULK_COUNT {
+            if processed >= bulk_count {
                 break;
             }
         }
         let elapsed_ms = start.elapsed()
             .map(|duration| { duration.as_millis() })
             .unwrap_or(0);
-        info!("Done resending votes ({} / {} | in {}ms)", count, self.cache.size(), elapsed_ms);
+        metrics::observe_resend_batch(processed, count);
+        let size = self.update_queue_metrics().await;
+        info!("Done resending votes to {} ({} / {} | in {}ms)", self.label(), count, size, elapsed_ms);
+    }
+
+    /**
+    Sets the gauges of this sink's queue and dead letters, returning the queue size
+    */
+    async fn update_queue_metrics(&self) -> usize {
+        let labels = [self.route.as_str(), self.name.as_str()];
+        let cache = self.cache.lock().await;
+        let size = cache.size().await;
+        metrics::QUEUE_SIZE.with_label_values(&labels).set(size as i64);
+        metrics::DEAD_LETTERS.with_label_values(&labels).set(cache.dead_letter_count().await as i64);
+        let age_ms = cache.oldest_enqueued_at().await
+            .map_or(0, |enqueued_at| now_millis().saturating_sub(enqueued_at));
+        metrics::OLDEST_QUEUED_AGE.with_label_values(&labels).set(age_ms as f64 / 1000.0);
+        return size;
     }
 
-    pub async fn forward_vote(&self, vote: VoteRequest) -> bool {
+    async fn retry_later(&mut self, mut cached: CachedVote, reason: String, retry_after: Option<Duration>) {
+        cached.record_failure(reason);
+        cached.next_attempt_at = next_attempt_at(cached.attempts, retry_after);
+        match exhausted_reason(&cached) {
+            Some(reason) => {
+                warn!("Moving vote {} of {} to dead letters: {}", cached.id, self.label(), reason);
+                metrics::DEAD_LETTERED.with_label_values(&["exhausted"]).inc();
+                self.cache.lock().await.dead_letter(cached, reason).await;
+            }
+            None => self.cache.lock().await.return_failed_retry(cached).await,
+        }
+    }
+
+    /**
+    Delivers the vote to the sink, timing the delivery by its outcome
+    */
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
         let start = SystemTime::now();
-        let serialized_vote = serde_json::to_string(&vote).unwrap();
-        let response = self.http_client.post(VOTE_ENDPOINT.clone().as_str())
-            .header("Authorization", VOTE_ENDPOINT_AUTH_TOKEN.clone().as_str())
-            .body(serialized_vote)
-            .send()
-            .await;
-        if response.is_ok() {
-            let response = response.unwrap();
-            let response = response.text().await;
-            if response.is_ok() {
-                let body = response.unwrap().clone();
-                let response = serde_json::from_str(body.as_str());
-                if response.is_ok() {
-                    let response: VoteResponse = response.unwrap();
-                    debug!("Response Status: {}", response.status);
-                    return response.status.eq("OK");
-                } else {
-                    error!("Serde: FAIL | body: {}", body);
+        let result = self.sink.deliver(vote).await;
+        let elapsed = start.elapsed().unwrap_or(Duration::ZERO);
+        metrics::FORWARD_LATENCY
+            .with_label_values(&[self.route.as_str(), self.name.as_str(), metrics::forward_outcome(&result)])
+            .observe(elapsed.as_secs_f64());
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use crate::vote_cache::{DeadLetter, MemoryVoteCache, QueueFilter};
+    use std::sync::Mutex as StdMutex;
+
+    /**
+    Records the users of the votes it is given after an optional delay, answering with the given result
+    */
+    struct TestSink {
+        delivered: Arc<StdMutex<Vec<u64>>>,
+        delay: Duration,
+        result: fn() -> ForwardResult,
+    }
+
+    #[async_trait]
+    impl VoteSink for TestSink {
+        async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+            if !self.delay.is_zero() {
+                tokio::time::sleep(self.delay).await;
+            }
+            self.delivered.lock().unwrap().push(vote.user.0);
+            return (self.result)();
+        }
+    }
+
+    fn delivered() -> ForwardResult {
+        return ForwardResult::Delivered;
+    }
+
+    fn unavailable() -> ForwardResult {
+        return ForwardResult::Retryable { reason: "unavailable".to_owned(), retry_after: None };
+    }
+
+    fn rejected() -> ForwardResult {
+        return ForwardResult::Rejected { reason: "bad request".to_owned() };
+    }
+
+    fn queue(name: &str, delay: Duration, result: fn() -> ForwardResult) -> (SinkQueue, Arc<StdMutex<Vec<u64>>>) {
+        let delivered = Arc::new(StdMutex::new(Vec::new()));
+        let sink = TestSink { delivered: delivered.clone(), delay, result };
+        let queue = SinkQueue::new(DEFAULT_ROUTE.to_owned(), name.to_owned(), Box::new(sink),
+                                   Box::new(MemoryVoteCache::new()));
+        return (queue, delivered);
+    }
+
+    fn vote(user: u64) -> VoteRequest {
+        let vote = format!(r#"{{"bot":"1","user":"{}","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}}"#, user);
+        return serde_json::from_str(vote.as_str()).unwrap();
+    }
+
+    /**
+    Waits up to five seconds for the condition to hold
+    */
+    async fn eventually<F: FnMut() -> bool>(mut condition: F) -> bool {
+        for _ in 0..500 {
+            if condition() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        return condition();
+    }
+
+    async fn cached(handler: &VoteHandler, name: &str) -> usize {
+        let queue = handler.queues.iter().find(|queue| queue.name == name).unwrap();
+        return queue.cache.lock().await.size().await;
+    }
+
+    async fn dead_letters(handler: &VoteHandler, name: &str) -> Vec<DeadLetter> {
+        let queue = handler.queues.iter().find(|queue| queue.name == name).unwrap();
+        return queue.cache.lock().await.dead_letters(0, 100).await;
+    }
+
+    const VOTES: u64 = QUEUE_CAPACITY as u64 + 50;
+
+    #[tokio::test]
+    async fn slow_sink_neither_delays_endpoint_nor_drops_votes() {
+        let (endpoint, endpoint_delivered) = queue(PRIMARY_SINK, Duration::ZERO, delivered);
+        let (slow, slow_delivered) = queue("slow", Duration::from_secs(3600), delivered);
+        let handler = VoteHandler::new(vec![endpoint, slow]);
+
+        for user in 0..VOTES {
+            handler.accept_vote_request(vote(user));
+            // like the processing loop, which awaits the next task in between
+            tokio::task::yield_now().await;
+        }
+
+        assert!(eventually(|| endpoint_delivered.lock().unwrap().len() as u64 == VOTES).await);
+        assert_eq!(*endpoint_delivered.lock().unwrap(), (0..VOTES).collect::<Vec<_>>());
+        assert_eq!(cached(&handler, PRIMARY_SINK).await, 0);
+        let slow_queue = handler.queues.iter().find(|queue| queue.name == "slow").unwrap();
+        let waiting = (QUEUE_CAPACITY - slow_queue.votes.capacity()) as u64;
+        // the first vote is stuck in delivery, the rest either waits in memory or was written to the cache
+        for _ in 0..500 {
+            if 1 + waiting + cached(&handler, "slow").await as u64 == VOTES {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(1 + waiting + cached(&handler, "slow").await as u64, VOTES);
+        assert!(slow_delivered.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failing_sink_caches_every_vote() {
+        let (endpoint, endpoint_delivered) = queue(PRIMARY_SINK, Duration::ZERO, delivered);
+        let (failing, failing_delivered) = queue("failing", Duration::from_millis(1), unavailable);
+        let handler = VoteHandler::new(vec![endpoint, failing]);
+
+        for user in 0..VOTES {
+            handler.accept_vote_request(vote(user));
+            // like the processing loop, which awaits the next task in between
+            tokio::task::yield_now().await;
+        }
+
+        assert!(eventually(|| endpoint_delivered.lock().unwrap().len() as u64 == VOTES).await);
+        for _ in 0..500 {
+            if cached(&handler, "failing").await as u64 == VOTES {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cached(&handler, "failing").await as u64, VOTES);
+        assert!(failing_delivered.lock().unwrap().len() as u64 <= VOTES);
+    }
+
+    /**
+    Panics on the first vote it is given, taking the task of its queue down
+    */
+    struct PanickingSink;
+
+    #[async_trait]
+    impl VoteSink for PanickingSink {
+        async fn deliver(&self, _vote: &VoteRequest) -> ForwardResult {
+            panic!("sink failed");
+        }
+    }
+
+    #[tokio::test]
+    async fn storage_check_answers_while_sink_is_delivering() {
+        let (slow, slow_delivered) = queue(PRIMARY_SINK, Duration::from_secs(3600), delivered);
+        let handler = VoteHandler::new(vec![slow]);
+        let probe = handler.probe();
+
+        handler.accept_vote_request(vote(1));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(slow_delivered.lock().unwrap().is_empty());
+        assert_eq!(probe.check_storage(Duration::from_secs(1)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn storage_check_fails_if_cache_does_not_answer_in_time() {
+        let (endpoint, _) = queue(PRIMARY_SINK, Duration::ZERO, delivered);
+        let handler = VoteHandler::new(vec![endpoint]);
+        let probe = handler.probe();
+
+        let queue = handler.queues.iter().find(|queue| queue.name == PRIMARY_SINK).unwrap();
+        let _guard = queue.cache.lock().await;
+        let ready = probe.check_storage(Duration::from_millis(50)).await;
+        assert_eq!(ready, Err("Cache did not respond in time".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn stopped_queue_is_reported() {
+        let (endpoint, _) = queue(PRIMARY_SINK, Duration::ZERO, delivered);
+        let failing = SinkQueue::new(DEFAULT_ROUTE.to_owned(), "failing".to_owned(), Box::new(PanickingSink),
+                                     Box::new(MemoryVoteCache::new()));
+        let handler = VoteHandler::new(vec![endpoint, failing]);
+        let probe = handler.probe();
+        assert!(probe.stopped().is_empty());
+
+        handler.accept_vote_request(vote(1));
+
+        assert!(eventually(|| !probe.stopped().is_empty()).await);
+        assert_eq!(probe.stopped(), vec![format!("{}/failing", DEFAULT_ROUTE)]);
+    }
+
+    #[tokio::test]
+    async fn bot_and_guild_votes_are_routed_to_their_queues() {
+        // routes bot 7 and guild 9 to routes of their own
+        config::install_test_config();
+        let queues = [DEFAULT_ROUTE, "bots", "guilds"].iter()
+            .map(|route| {
+                let sink = TestSink { delivered: Arc::default(), delay: Duration::ZERO, result: unavailable };
+                SinkQueue::new(route.to_string(), PRIMARY_SINK.to_owned(), Box::new(sink),
+                               Box::new(MemoryVoteCache::new()))
+            })
+            .collect();
+        let handler = VoteHandler::new(queues);
+
+        let bot_vote = r#"{"bot":"7","user":"1","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}"#;
+        let guild_vote = r#"{"bot":"0","guild":"9","kind":"guild","user":"2","type":"upvote","isWeekend":false,
+                             "query":null,"src":"topgg"}"#;
+        handler.accept_vote_request(serde_json::from_str(bot_vote).unwrap());
+        handler.accept_vote_request(serde_json::from_str(guild_vote).unwrap());
+
+        let queued = |route: &'static str, filter: QueueFilter| {
+            let queue = handler.queues.iter().find(|queue| queue.route == route).unwrap().clone();
+            async move {
+                let (total, votes) = queue.cache.lock().await.queued(&filter, 0, 100).await;
+                return (total, votes.iter().map(|vote| vote.vote.user.0).collect::<Vec<_>>());
+            }
+        };
+        for _ in 0..500 {
+            if queued("bots", QueueFilter::default()).await.0 + queued("guilds", QueueFilter::default()).await.0 == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let by_bot = QueueFilter { bot: Some(7), ..QueueFilter::default() };
+        let by_guild = QueueFilter { guild: Some(9), ..QueueFilter::default() };
+        assert_eq!(queued("bots", by_bot.clone()).await, (1, vec![1]));
+        assert_eq!(queued("bots", by_guild.clone()).await, (0, vec![]));
+        assert_eq!(queued("guilds", by_guild).await, (1, vec![2]));
+        assert_eq!(queued("guilds", by_bot).await, (0, vec![]));
+        assert_eq!(queued(DEFAULT_ROUTE, QueueFilter::default()).await, (0, vec![]));
+    }
+
+    #[tokio::test]
+    async fn rejected_vote_is_dead_lettered_right_away() {
+        let (endpoint, endpoint_delivered) = queue(PRIMARY_SINK, Duration::ZERO, rejected);
+        let handler = VoteHandler::new(vec![endpoint]);
+
+        handler.accept_vote_request(vote(1));
+
+        for _ in 0..500 {
+            if !dead_letters(&handler, PRIMARY_SINK).await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let dead = dead_letters(&handler, PRIMARY_SINK).await;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].vote.vote.user.0, 1);
+        assert_eq!(dead[0].vote.attempts, 1);
+        assert_eq!(dead[0].reason, "bad request");
+        assert_eq!(cached(&handler, PRIMARY_SINK).await, 0);
+        assert_eq!(*endpoint_delivered.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn exhausted_vote_is_dead_lettered_after_max_attempts() {
+        // gives up after three attempts
+        config::install_test_config();
+        let (endpoint, endpoint_delivered) = queue(PRIMARY_SINK, Duration::ZERO, unavailable);
+        let handler = VoteHandler::new(vec![endpoint]);
+
+        handler.accept_vote_request(vote(1));
+        for attempts in 1..3 {
+            // the vote stays queued until its third attempt failed
+            assert!(eventually(|| endpoint_delivered.lock().unwrap().len() == attempts).await);
+            for _ in 0..500 {
+                if cached(&handler, PRIMARY_SINK).await == 1 {
+                    break;
                 }
-            } else {
-                error!("Body: FAIL | ???");
+                tokio::time::sleep(Duration::from_millis(10)).await;
             }
-        } else {
-            let elapsed_ms = start.elapsed()
-                .map(|duration| { duration.as_millis() })
-                .unwrap_or(0);
-            warn!("Request to vote-endpoint failed after {}ms!", elapsed_ms)
+            assert_eq!(cached(&handler, PRIMARY_SINK).await, 1);
+            assert!(dead_letters(&handler, PRIMARY_SINK).await.is_empty());
+
+            let (reply, answer) = oneshot::channel();
+            handler.process(CacheTask::create_force_resend_task(QueueSelector::default(), reply));
+            assert_eq!(answer.await.unwrap()["expedited"], 1);
         }
-        return false;
+
+        for _ in 0..500 {
+            if !dead_letters(&handler, PRIMARY_SINK).await.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let dead = dead_letters(&handler, PRIMARY_SINK).await;
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].vote.attempts, 3);
+        assert_eq!(dead[0].reason, "Gave up after 3 attempts: unavailable");
+        assert_eq!(cached(&handler, PRIMARY_SINK).await, 0);
     }
-}
\ No newline at end of file
+}
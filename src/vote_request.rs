@@ -1,5 +1,6 @@
 use crate::snowflake::Snowflake;
-use crate::constants::{PAGE_KEY_TOPGG, PAGE_KEY_DBL, PAGE_KEY_BFD, PAGE_KEY_DBOATS, PAGE_KEY_DLIST};
+use crate::constants::{VOTE_KIND_BOT, VOTE_KIND_GUILD, PAGE_KEY_TOPGG, PAGE_KEY_DBL, PAGE_KEY_DBLCOM, PAGE_KEY_BFD, PAGE_KEY_DBOATS, PAGE_KEY_IBL, PAGE_KEY_VOIDBOTS,
+                       PAGE_KEY_DLABS, PAGE_KEY_BOTLISTME, PAGE_KEY_DLIST};
 use serde::{Serialize, Deserialize};
 
 pub trait Vote {
@@ -7,6 +8,20 @@ pub trait Vote {
     fn get_user(&self) -> Snowflake;
     fn get_source(&self) -> String;
     fn get_as_generic(&self) -> VoteRequest;
+
+    /**
+    Avatar hash of the voter, for lists which send it along with the vote
+    */
+    fn get_avatar(&self) -> Option<String> {
+        return None;
+    }
+
+    /**
+    Whether the voter is an admin of the list, for lists which send it along with the vote
+    */
+    fn is_admin(&self) -> Option<bool> {
+        return None;
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -18,12 +33,68 @@ pub struct VoteRequest {
     pub is_weekend: bool,
     pub query: Option<String>,
     pub src: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<u64>,
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guild: Option<Snowflake>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin: Option<bool>,
+}
+
+fn default_kind() -> String {
+    return VOTE_KIND_BOT.to_owned();
+}
+
+impl VoteRequest {
+    /**
+    The id of what was voted for, the guild of a server vote or else the bot
+    */
+    pub fn target(&self) -> Snowflake {
+        return self.guild.unwrap_or(self.bot);
+    }
+}
+
+/**
+A vote as emitted to event streams, with the metadata consumers need to order and deduplicate it
+*/
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VoteEnvelope<'a> {
+    pub version: u8,
+    pub received_at: Option<u64>,
+    pub source: String,
+    pub route: &'a str,
+    pub idempotency_key: Option<&'a str>,
+    pub vote: &'a VoteRequest,
+}
+
+impl<'a> VoteEnvelope<'a> {
+    pub fn new(vote: &'a VoteRequest, route: &'a str) -> VoteEnvelope<'a> {
+        return VoteEnvelope {
+            version: 1,
+            received_at: vote.received_at,
+            source: vote.get_source(),
+            route,
+            idempotency_key: vote.idempotency_key.as_deref(),
+            vote,
+        };
+    }
 }
 
+/**
+A vote for a bot, or with `guild` instead of `bot` for a server
+*/
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct TopVoteRequest {
-    pub bot: Snowflake,
+    pub bot: Option<Snowflake>,
+    pub guild: Option<Snowflake>,
     pub user: Snowflake,
     pub r#type: String,
     pub is_weekend: Option<bool>,
@@ -37,6 +108,21 @@ pub struct DblComVoteRequest {
     pub username: String,
 }
 
+/**
+Current webhook of discordbotlist.com, the bot is only known from the path of the webhook url.
+`avatar` is null for voters without one and `admin` is missing from some older deliveries.
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DblComV2VoteRequest {
+    #[serde(skip)]
+    pub bot: Option<Snowflake>,
+    pub id: Snowflake,
+    pub username: String,
+    pub avatar: Option<String>,
+    #[serde(default)]
+    pub admin: bool,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct BfdVoteRequest {
     pub bot: Snowflake,
@@ -44,6 +130,24 @@ pub struct BfdVoteRequest {
     pub r#type: String,
 }
 
+/**
+Webhook v2 of discords.com (formerly botsfordiscord), sent for votes on bots as well as servers.
+Exactly one of `bot` and `guild` is set, the vote counts it also carries are not forwarded.
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DiscordsVoteRequest {
+    pub bot: Option<Snowflake>,
+    pub guild: Option<Snowflake>,
+    pub user: Snowflake,
+    pub r#type: String,
+}
+
+impl DiscordsVoteRequest {
+    pub fn has_single_target(&self) -> bool {
+        return self.bot.is_some() != self.guild.is_some();
+    }
+}
+
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DBoatsBotData {
@@ -64,6 +168,60 @@ pub struct DBoatsVoteRequest {
     pub user: DBoatsUserData,
 }
 
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IblEntity {
+    pub id: Snowflake,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IblTargets {
+    pub bot: Option<IblEntity>,
+}
+
+/**
+Decrypted payload of an Infinity Bot List v2 webhook, the voter is the creator of the event
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IblVoteRequest {
+    pub r#type: String,
+    pub creator: IblEntity,
+    pub targets: IblTargets,
+}
+
+impl IblVoteRequest {
+    pub fn is_vote(&self) -> bool {
+        return self.r#type.eq("NEW_BOT_VOTE") && self.targets.bot.is_some();
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VoidBotsVoteRequest {
+    pub bot: Snowflake,
+    pub user: Snowflake,
+    pub r#type: Option<String>,
+}
+
+/**
+Discord Labs sends its secret in the `token` field of the body rather than a header
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DiscordLabsVoteRequest {
+    pub uid: Snowflake,
+    pub bid: Snowflake,
+    #[serde(default)]
+    pub test: bool,
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BotListMeVoteRequest {
+    #[serde(alias = "bot_id")]
+    pub bot: Snowflake,
+    #[serde(alias = "user_id")]
+    pub user: Snowflake,
+    pub r#type: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct DiscordListVoteRequest {
     pub bot_id: Snowflake,
@@ -93,13 +251,25 @@ impl Vote for VoteRequest {
             is_weekend: self.is_weekend,
             query: self.query.to_owned(),
             src: Some(self.get_source()),
+            idempotency_key: self.idempotency_key.to_owned(),
+            received_at: self.received_at,
+            kind: self.kind.to_owned(),
+            guild: self.guild,
+            avatar: None,
+            admin: None,
         };
     }
 }
 
+impl TopVoteRequest {
+    pub fn has_single_target(&self) -> bool {
+        return self.bot.is_some() != self.guild.is_some();
+    }
+}
+
 impl Vote for TopVoteRequest {
     fn get_bot(&self) -> Snowflake {
-        return self.bot;
+        return self.bot.unwrap_or(Snowflake(0));
     }
 
     fn get_user(&self) -> Snowflake {
@@ -118,6 +288,12 @@ impl Vote for TopVoteRequest {
             is_weekend: self.is_weekend.unwrap_or(false),
             query: self.query.to_owned(),
             src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: if self.guild.is_some() { VOTE_KIND_GUILD.to_owned() } else { VOTE_KIND_BOT.to_owned() },
+            guild: self.guild,
+            avatar: None,
+            admin: None,
         };
     }
 }
@@ -143,6 +319,51 @@ impl Vote for DblComVoteRequest {
             is_weekend: false,
             query: None,
             src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
+        };
+    }
+}
+
+impl Vote for DblComV2VoteRequest {
+    fn get_bot(&self) -> Snowflake {
+        return self.bot.unwrap_or(Snowflake(0));
+    }
+
+    fn get_user(&self) -> Snowflake {
+        return self.id;
+    }
+
+    fn get_source(&self) -> String {
+        return PAGE_KEY_DBLCOM.to_owned();
+    }
+
+    fn get_avatar(&self) -> Option<String> {
+        return self.avatar.clone();
+    }
+
+    fn is_admin(&self) -> Option<bool> {
+        return Some(self.admin);
+    }
+
+    fn get_as_generic(&self) -> VoteRequest {
+        return VoteRequest {
+            bot: self.get_bot(),
+            user: self.get_user(),
+            r#type: "vote".to_owned(),
+            is_weekend: false,
+            query: None,
+            src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: self.get_avatar(),
+            admin: self.is_admin(),
         };
     }
 }
@@ -168,6 +389,43 @@ impl Vote for BfdVoteRequest {
             is_weekend: false,
             query: None,
             src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
+        };
+    }
+}
+
+impl Vote for DiscordsVoteRequest {
+    fn get_bot(&self) -> Snowflake {
+        return self.bot.unwrap_or(Snowflake(0));
+    }
+
+    fn get_user(&self) -> Snowflake {
+        return self.user;
+    }
+
+    fn get_source(&self) -> String {
+        return PAGE_KEY_BFD.to_owned();
+    }
+
+    fn get_as_generic(&self) -> VoteRequest {
+        return VoteRequest {
+            bot: self.get_bot(),
+            user: self.get_user(),
+            r#type: if self.r#type.eq("test") { "test".to_owned() } else { "vote".to_owned() },
+            is_weekend: false,
+            query: None,
+            src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: if self.guild.is_some() { VOTE_KIND_GUILD.to_owned() } else { VOTE_KIND_BOT.to_owned() },
+            guild: self.guild,
+            avatar: None,
+            admin: None,
         };
     }
 }
@@ -193,6 +451,136 @@ impl Vote for DBoatsVoteRequest {
             is_weekend: false,
             query: None,
             src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
+        };
+    }
+}
+
+impl Vote for IblVoteRequest {
+    fn get_bot(&self) -> Snowflake {
+        return self.targets.bot.as_ref().map_or(Snowflake(0), |bot| bot.id);
+    }
+
+    fn get_user(&self) -> Snowflake {
+        return self.creator.id;
+    }
+
+    fn get_source(&self) -> String {
+        return PAGE_KEY_IBL.to_owned();
+    }
+
+    fn get_as_generic(&self) -> VoteRequest {
+        return VoteRequest {
+            bot: self.get_bot(),
+            user: self.get_user(),
+            r#type: "vote".to_owned(),
+            is_weekend: false,
+            query: None,
+            src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
+        };
+    }
+}
+
+impl Vote for VoidBotsVoteRequest {
+    fn get_bot(&self) -> Snowflake {
+        return self.bot;
+    }
+
+    fn get_user(&self) -> Snowflake {
+        return self.user;
+    }
+
+    fn get_source(&self) -> String {
+        return PAGE_KEY_VOIDBOTS.to_owned();
+    }
+
+    fn get_as_generic(&self) -> VoteRequest {
+        return VoteRequest {
+            bot: self.get_bot(),
+            user: self.get_user(),
+            r#type: if self.r#type.as_deref() == Some("test") { "test".to_owned() } else { "vote".to_owned() },
+            is_weekend: false,
+            query: None,
+            src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
+        };
+    }
+}
+
+impl Vote for DiscordLabsVoteRequest {
+    fn get_bot(&self) -> Snowflake {
+        return self.bid;
+    }
+
+    fn get_user(&self) -> Snowflake {
+        return self.uid;
+    }
+
+    fn get_source(&self) -> String {
+        return PAGE_KEY_DLABS.to_owned();
+    }
+
+    fn get_as_generic(&self) -> VoteRequest {
+        return VoteRequest {
+            bot: self.get_bot(),
+            user: self.get_user(),
+            r#type: if self.test { "test".to_owned() } else { "vote".to_owned() },
+            is_weekend: false,
+            query: None,
+            src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
+        };
+    }
+}
+
+impl Vote for BotListMeVoteRequest {
+    fn get_bot(&self) -> Snowflake {
+        return self.bot;
+    }
+
+    fn get_user(&self) -> Snowflake {
+        return self.user;
+    }
+
+    fn get_source(&self) -> String {
+        return PAGE_KEY_BOTLISTME.to_owned();
+    }
+
+    fn get_as_generic(&self) -> VoteRequest {
+        return VoteRequest {
+            bot: self.get_bot(),
+            user: self.get_user(),
+            r#type: if self.r#type.as_deref() == Some("test") { "test".to_owned() } else { "vote".to_owned() },
+            is_weekend: false,
+            query: None,
+            src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
         };
     }
 }
@@ -219,6 +607,119 @@ impl Vote for DiscordListVoteRequest {
             is_weekend: false,
             query: self.query.clone(),
             src: Some(self.get_source()),
+            idempotency_key: None,
+            received_at: None,
+            kind: VOTE_KIND_BOT.to_owned(),
+            guild: None,
+            avatar: None,
+            admin: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::DEFAULT_ROUTE;
+
+    fn dblcom_vote(fixture: &str, bot: u64) -> VoteRequest {
+        let mut vote: DblComV2VoteRequest = serde_json::from_str(fixture).unwrap();
+        vote.bot = Some(Snowflake(bot));
+        return vote.get_as_generic();
+    }
+
+    #[test]
+    fn dblcom_webhook_is_forwarded_as_vote_of_path_bot() {
+        let vote = dblcom_vote(include_str!("../tests/fixtures/dblcom_vote.json"), 42);
+        assert_eq!(vote.bot, Snowflake(42));
+        assert_eq!(vote.user, Snowflake(215183283311542272));
+        assert_eq!(vote.r#type, "vote");
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_DBLCOM));
+        assert_eq!(vote.kind, VOTE_KIND_BOT);
+        assert!(!vote.is_weekend);
+        assert_eq!(vote.avatar.as_deref(), Some("a_4c6e3f5d0b2f8c1e9a7d6b5c4e3f2a1b"));
+        assert_eq!(vote.admin, Some(false));
+    }
+
+    #[test]
+    fn dblcom_webhook_of_admin_without_avatar_is_accepted() {
+        let vote = dblcom_vote(include_str!("../tests/fixtures/dblcom_vote_admin.json"), 42);
+        assert_eq!(vote.user, Snowflake(356950275044671499));
+        assert_eq!(vote.r#type, "vote");
+        assert_eq!(vote.avatar, None);
+        assert_eq!(vote.admin, Some(true));
+    }
+
+    #[test]
+    fn dblcom_webhook_forwards_avatar_and_admin() {
+        let vote = dblcom_vote(include_str!("../tests/fixtures/dblcom_vote.json"), 42);
+        let forwarded = serde_json::to_value(&vote).unwrap();
+        assert_eq!(forwarded["avatar"], "a_4c6e3f5d0b2f8c1e9a7d6b5c4e3f2a1b");
+        assert_eq!(forwarded["admin"], false);
+
+        let vote = dblcom_vote(r#"{"id":"215183283311542272","username":"zerotwo","avatar":null}"#, 42);
+        assert_eq!(vote.admin, Some(false));
+        let forwarded = serde_json::to_value(&vote).unwrap();
+        assert!(forwarded.get("avatar").is_none());
+    }
+
+    #[test]
+    fn legacy_dbl_webhook_keeps_its_source() {
+        let mut vote: DblComVoteRequest =
+            serde_json::from_str(include_str!("../tests/fixtures/dbl_vote_legacy.json")).unwrap();
+        vote.bot = Some(Snowflake(42));
+        let vote = vote.get_as_generic();
+        assert_eq!(vote.bot, Snowflake(42));
+        assert_eq!(vote.user, Snowflake(215183283311542272));
+        assert_eq!(vote.src.as_deref(), Some(PAGE_KEY_DBL));
+        let forwarded = serde_json::to_value(&vote).unwrap();
+        assert!(forwarded.get("avatar").is_none());
+        assert!(forwarded.get("admin").is_none());
+    }
+
+    #[test]
+    fn dblcom_webhook_without_id_is_refused() {
+        assert!(serde_json::from_str::<DblComV2VoteRequest>(r#"{"username":"zerotwo","admin":false}"#).is_err());
+    }
+
+    #[test]
+    fn envelope_carries_version_time_and_idempotency_key() {
+        let mut vote: VoteRequest = serde_json::from_str(
+            r#"{"bot":"42","user":"1","type":"vote","isWeekend":true,"query":"?a=b","src":"topgg"}"#).unwrap();
+        vote.received_at = Some(1_700_000_000_000);
+        vote.idempotency_key = Some("abc".to_owned());
+
+        let envelope = serde_json::to_value(VoteEnvelope::new(&vote, "music")).unwrap();
+        assert_eq!(envelope, serde_json::json!({
+            "version": 1,
+            "receivedAt": 1_700_000_000_000u64,
+            "source": "topgg",
+            "route": "music",
+            "idempotencyKey": "abc",
+            "vote": {
+                "bot": "42",
+                "user": "1",
+                "type": "vote",
+                "isWeekend": true,
+                "query": "?a=b",
+                "src": "topgg",
+                "idempotencyKey": "abc",
+                "receivedAt": 1_700_000_000_000u64,
+                "kind": "bot"
+            }
+        }));
+    }
+
+    #[test]
+    fn envelope_of_vote_without_metadata_has_null_fields() {
+        let vote: VoteRequest = serde_json::from_str(
+            r#"{"bot":"0","guild":"9","kind":"guild","user":"1","type":"vote","isWeekend":false,"query":null,"src":"bfd"}"#)
+            .unwrap();
+        let envelope = serde_json::to_value(VoteEnvelope::new(&vote, DEFAULT_ROUTE)).unwrap();
+        assert_eq!(envelope["receivedAt"], serde_json::Value::Null);
+        assert_eq!(envelope["idempotencyKey"], serde_json::Value::Null);
+        assert_eq!(envelope["source"], "bfd");
+        assert_eq!(envelope["vote"]["guild"], "9");
+        assert_eq!(envelope["vote"]["kind"], "guild");
+    }
+}
@@ -0,0 +1,152 @@
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use std::time::SystemTime;
+use crate::config;
+use crate::amqp_sink::AmqpSink;
+use crate::constants::{PRIMARY_SINK, SINK_KIND_HTTP, SINK_KIND_DISCORD, SINK_KIND_AMQP, SINK_KIND_KAFKA, SINK_KIND_NATS,
+                       SINK_KIND_GRPC};
+use crate::discord_sink::DiscordSink;
+use crate::grpc_sink::GrpcSink;
+use crate::kafka_sink::KafkaSink;
+use crate::nats_sink::NatsSink;
+use crate::forward_result::ForwardResult;
+use crate::vote_request::VoteRequest;
+
+/**
+A destination of votes. Its settings are looked up in the current config on every delivery
+so they can be changed by a reload.
+*/
+#[async_trait]
+pub trait VoteSink: Send + Sync {
+    /**
+    Delivers the vote and classifies the outcome
+    */
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult;
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+struct VoteResponse {
+    status: String
+}
+
+/**
+Posts votes as JSON. The endpoint of a route has to answer with `{"status":"OK"}`,
+further http sinks with any 2xx status
+*/
+pub struct HttpSink {
+    http_client: Client,
+    route: String,
+    name: String,
+}
+
+impl HttpSink {
+    pub fn new(http_client: Client, route: &str, name: &str) -> HttpSink {
+        return HttpSink {
+            http_client,
+            route: route.to_owned(),
+            name: name.to_owned(),
+        };
+    }
+
+    fn is_primary(&self) -> bool {
+        return self.name == PRIMARY_SINK;
+    }
+}
+
+#[async_trait]
+impl VoteSink for HttpSink {
+    async fn deliver(&self, vote: &VoteRequest) -> ForwardResult {
+        let start = SystemTime::now();
+        let serialized_vote = serde_json::to_string(vote).unwrap();
+        let config = config::get();
+        let (endpoint, auth_token) = match config.sink_target(self.route.as_str(), self.name.as_str()) {
+            Some(target) => target,
+            None => {
+                return ForwardResult::Retryable {
+                    reason: format!("Sink {} of route {} is not configured", self.name, self.route),
+                    retry_after: None,
+                };
+            }
+        };
+        let mut request = self.http_client.post(endpoint)
+            .timeout(config.sink_timeout());
+        if let Some(auth_token) = auth_token {
+            request = request.header("Authorization", auth_token);
+        }
+        if let Some(key) = vote.idempotency_key.as_ref() {
+            request = request.header("Idempotency-Key", key.as_str());
+        }
+        let response = request
+            .body(serialized_vote)
+            .send()
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(err) => {
+                let elapsed_ms = start.elapsed()
+                    .map(|duration| { duration.as_millis() })
+                    .unwrap_or(0);
+                warn!("Request to {} of route {} failed after {}ms!", self.name, self.route, elapsed_ms);
+                return ForwardResult::Retryable {
+                    reason: format!("Request failed after {}ms: {}", elapsed_ms, err),
+                    retry_after: None,
+                };
+            }
+        };
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Body: FAIL | {}", err);
+                return ForwardResult::Retryable {
+                    reason: format!("Failed to read response body: {}", err),
+                    retry_after: None,
+                };
+            }
+        };
+        if !status.is_success() {
+            warn!("{} of route {} responded with {}", self.name, self.route, status);
+            return ForwardResult::from_http_error(status, &headers, body.as_str());
+        }
+        if !self.is_primary() {
+            return ForwardResult::Delivered;
+        }
+        return match serde_json::from_str::<VoteResponse>(body.as_str()) {
+            Ok(response) if response.status.eq("OK") => {
+                debug!("Response Status: {}", response.status);
+                ForwardResult::Delivered
+            }
+            Ok(response) => {
+                warn!("Vote endpoint of route {} rejected vote with status {}", self.route, response.status);
+                ForwardResult::Rejected {
+                    reason: format!("Vote endpoint responded with status {}", response.status),
+                }
+            }
+            Err(_) => {
+                error!("Serde: FAIL | body: {}", body);
+                ForwardResult::Retryable {
+                    reason: format!("Unparsable response body: {}", body),
+                    retry_after: None,
+                }
+            }
+        };
+    }
+}
+
+/**
+Creates the sink of the given kind, the endpoint of a route is an http or grpc sink
+*/
+pub fn create_vote_sink(http_client: &Client, route: &str, name: &str, kind: &str) -> Box<dyn VoteSink> {
+    return match kind {
+        SINK_KIND_HTTP => Box::new(HttpSink::new(http_client.clone(), route, name)),
+        SINK_KIND_DISCORD => Box::new(DiscordSink::new(http_client.clone(), route, name)),
+        SINK_KIND_GRPC => Box::new(GrpcSink::new(route, name)),
+        SINK_KIND_AMQP => Box::new(AmqpSink::new(route, name)),
+        SINK_KIND_KAFKA => Box::new(KafkaSink::new(route, name)),
+        SINK_KIND_NATS => Box::new(NatsSink::new(route, name)),
+        kind => panic!("Unknown sink kind: {}", kind),
+    };
+}
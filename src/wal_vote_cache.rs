@@ -0,0 +1,509 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use serde::{Serialize, Deserialize};
+use crate::vote_cache::{VoteCache, CachedVote, DeadLetter, QueueFilter, now_millis, next_eligible, page_queued};
+use crate::vote_request::VoteRequest;
+
+const SEGMENT_PREFIX: &str = "segment-";
+const SEGMENT_EXTENSION: &str = "log";
+
+/**
+A single mutation of the cache, appended as one JSON line to the active segment
+*/
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum WalRecord {
+    Push { vote: CachedVote },
+    PushFront { vote: CachedVote },
+    Pop { id: u64 },
+    Dead { letter: DeadLetter },
+    Requeue { vote: CachedVote },
+    Purge { id: Option<u64> },
+    Expedite { at: u64 },
+}
+
+/**
+Live state of the cache as rebuilt from the log
+*/
+#[derive(Default)]
+struct WalState {
+    entries: VecDeque<CachedVote>,
+    dead: BTreeMap<u64, DeadLetter>,
+}
+
+/**
+Vote cache which keeps its queue in memory and writes every mutation through to
+an append-only log of segment files before returning.
+
+On open all segments are replayed in order and compacted into a fresh segment
+holding only the live entries; the same compaction runs whenever more than the
+configured segment size has been appended since the last compaction.
+
+Polling does not touch the log, a vote is only removed from it once acknowledged,
+so votes which were in flight during a crash are delivered again after a restart.
+
+A failed write leaves the cache not ready until the next mutation manages to compact the
+live entries into a fresh segment, which also drops whatever was partially written.
+*/
+pub struct WalVoteCache {
+    dir: PathBuf,
+    entries: VecDeque<CachedVote>,
+    dead: BTreeMap<u64, DeadLetter>,
+    in_flight: HashMap<u64, CachedVote>,
+    next_id: u64,
+    segment_id: u64,
+    segment: File,
+    segment_size: u64,
+    snapshot_size: u64,
+    max_segment_size: u64,
+    write_error: Option<String>,
+}
+
+impl WalVoteCache {
+    pub fn open(dir: &Path, max_segment_size: u64) -> io::Result<WalVoteCache> {
+        fs::create_dir_all(dir)?;
+        let mut state = WalState::default();
+        let segments = list_segments(dir)?;
+        for (_, path) in &segments {
+            replay_segment(path, &mut state)?;
+        }
+        let WalState { entries, dead } = state;
+        let next_id = entries.iter().map(|vote| vote.id + 1)
+            .chain(dead.keys().map(|id| id + 1))
+            .max()
+            .unwrap_or(0);
+        let segment_id = segments.last().map_or(0, |(id, _)| *id) + 1;
+        let (segment, segment_size) = write_snapshot(dir, segment_id, entries.iter(), dead.values())?;
+        return Ok(WalVoteCache {
+            dir: dir.to_path_buf(),
+            entries,
+            dead,
+            in_flight: HashMap::new(),
+            next_id,
+            segment_id,
+            segment,
+            segment_size,
+            snapshot_size: segment_size,
+            max_segment_size,
+            write_error: None,
+        });
+    }
+
+    /**
+    Makes a mutation which was already applied in memory durable. After a failed write the log
+    can't be trusted anymore, so it is rewritten from memory instead until that succeeds
+    */
+    fn record(&mut self, record: &WalRecord) {
+        let result = match self.write_error {
+            Some(_) => self.compact(),
+            None => self.append(record),
+        };
+        match result {
+            Ok(()) if self.write_error.is_some() => {
+                warn!("Recovered vote cache log {}", self.dir.display());
+                self.write_error = None;
+            }
+            Ok(()) => {}
+            Err(err) => {
+                error!("Failed to write to vote cache log {}: {}", self.dir.display(), err);
+                self.write_error = Some(err.to_string());
+            }
+        }
+    }
+
+    fn append(&mut self, record: &WalRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record).unwrap();
+        line.push(b'\n');
+        self.segment.write_all(&line)?;
+        self.segment.sync_data()?;
+        self.segment_size += line.len() as u64;
+        if self.segment_size - self.snapshot_size >= self.max_segment_size {
+            // the record is durable already, the old segments stay valid until a compaction succeeds
+            if let Err(err) = self.compact() {
+                error!("Failed to compact vote cache log {}: {}", self.dir.display(), err);
+            }
+        }
+        return Ok(());
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let segment_id = self.segment_id + 1;
+        let live = self.in_flight.values().chain(self.entries.iter());
+        let (segment, segment_size) = write_snapshot(&self.dir, segment_id, live, self.dead.values())?;
+        debug!("Compacted vote cache log into segment {} ({} entries)", segment_id,
+               self.entries.len() + self.in_flight.len());
+        self.segment_id = segment_id;
+        self.segment = segment;
+        self.segment_size = segment_size;
+        self.snapshot_size = segment_size;
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl VoteCache for WalVoteCache {
+    async fn cache_failed_vote(&mut self, vote: VoteRequest, error: String, next_attempt_at: u64) {
+        let vote = CachedVote::first_failure(self.next_id, vote, error, next_attempt_at);
+        self.next_id += 1;
+        self.entries.push_back(vote.clone());
+        self.record(&WalRecord::Push { vote });
+    }
+
+    async fn return_failed_retry(&mut self, vote: CachedVote) {
+        self.in_flight.remove(&vote.id);
+        self.entries.push_front(vote.clone());
+        self.record(&WalRecord::PushFront { vote });
+    }
+
+    async fn poll(&mut self) -> Option<CachedVote> {
+        let index = next_eligible(&self.entries)?;
+        let vote = self.entries.remove(index)?;
+        self.in_flight.insert(vote.id, vote.clone());
+        return Some(vote);
+    }
+
+    async fn ack(&mut self, vote: &CachedVote) {
+        self.in_flight.remove(&vote.id);
+        self.record(&WalRecord::Pop { id: vote.id });
+    }
+
+    async fn size(&self) -> usize {
+        self.entries.len() + self.in_flight.len()
+    }
+
+    async fn check_ready(&self) -> Result<(), String> {
+        if let Some(err) = self.write_error.as_ref() {
+            return Err(format!("Vote cache log {} failed to write: {}", self.dir.display(), err));
+        }
+        return self.segment.metadata()
+            .map(|_| ())
+            .map_err(|err| format!("Vote cache log {} unavailable: {}", self.dir.display(), err));
+    }
+
+    async fn oldest_enqueued_at(&self) -> Option<u64> {
+        return self.entries.iter().chain(self.in_flight.values()).map(|vote| vote.enqueued_at).min();
+    }
+
+    async fn dead_letter(&mut self, vote: CachedVote, reason: String) {
+        self.in_flight.remove(&vote.id);
+        let letter = DeadLetter { vote, reason, dead_at: now_millis() };
+        self.dead.insert(letter.vote.id, letter.clone());
+        self.record(&WalRecord::Dead { letter });
+    }
+
+    async fn dead_letter_failed_vote(&mut self, vote: VoteRequest, reason: String) {
+        let vote = CachedVote::first_failure(self.next_id, vote, reason.clone(), now_millis());
+        self.next_id += 1;
+        let letter = DeadLetter { dead_at: vote.enqueued_at, vote, reason };
+        self.dead.insert(letter.vote.id, letter.clone());
+        self.record(&WalRecord::Dead { letter });
+    }
+
+    async fn dead_letters(&self, offset: usize, limit: usize) -> Vec<DeadLetter> {
+        return self.dead.values().skip(offset).take(limit).cloned().collect();
+    }
+
+    async fn dead_letter_count(&self) -> usize {
+        self.dead.len()
+    }
+
+    async fn requeue_dead_letter(&mut self, id: u64) -> bool {
+        let vote = match self.dead.remove(&id) {
+            Some(letter) => letter.into_requeued(),
+            None => return false,
+        };
+        self.entries.push_back(vote.clone());
+        self.record(&WalRecord::Requeue { vote });
+        return true;
+    }
+
+    async fn purge_dead_letters(&mut self, id: Option<u64>) -> usize {
+        let count = match id {
+            Some(id) => self.dead.remove(&id).map_or(0, |_| 1),
+            None => {
+                let count = self.dead.len();
+                self.dead.clear();
+                count
+            }
+        };
+        if count > 0 {
+            self.record(&WalRecord::Purge { id });
+        }
+        return count;
+    }
+
+    async fn queued(&self, filter: &QueueFilter, offset: usize, limit: usize) -> (usize, Vec<CachedVote>) {
+        return page_queued(self.entries.iter().chain(self.in_flight.values()), filter, offset, limit);
+    }
+
+    async fn queued_vote(&self, id: u64) -> Option<CachedVote> {
+        return self.entries.iter().find(|vote| vote.id == id)
+            .or_else(|| self.in_flight.get(&id))
+            .cloned();
+    }
+
+    async fn remove_queued(&mut self, id: u64) -> bool {
+        let size = self.entries.len();
+        remove_entry(&mut self.entries, id);
+        let removed = self.entries.len() < size || self.in_flight.remove(&id).is_some();
+        if removed {
+            self.record(&WalRecord::Pop { id });
+        }
+        return removed;
+    }
+
+    async fn expedite_queued(&mut self) -> usize {
+        let now = now_millis();
+        self.entries.iter_mut().for_each(|vote| vote.next_attempt_at = now);
+        self.record(&WalRecord::Expedite { at: now });
+        return self.entries.len();
+    }
+}
+
+fn segment_path(dir: &Path, segment_id: u64) -> PathBuf {
+    return dir.join(format!("{}{:020}.{}", SEGMENT_PREFIX, segment_id, SEGMENT_EXTENSION));
+}
+
+fn list_segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != SEGMENT_EXTENSION) {
+            continue;
+        }
+        let segment_id = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|id| id.parse::<u64>().ok());
+        if let Some(segment_id) = segment_id {
+            segments.push((segment_id, path));
+        }
+    }
+    segments.sort_by_key(|(segment_id, _)| *segment_id);
+    return Ok(segments);
+}
+
+fn replay_segment(path: &Path, state: &mut WalState) -> io::Result<()> {
+    let reader = BufReader::new(File::open(path)?);
+    for (line_no, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let record: WalRecord = match serde_json::from_str(line.as_str()) {
+            Ok(record) => record,
+            Err(err) => {
+                // A torn write at the end of the last segment is expected after a crash
+                warn!("Skipping unreadable record {}:{}: {}", path.display(), line_no + 1, err);
+                continue;
+            }
+        };
+        match record {
+            WalRecord::Push { vote } => {
+                state.entries.push_back(vote);
+            }
+            WalRecord::PushFront { vote } => {
+                remove_entry(&mut state.entries, vote.id);
+                state.entries.push_front(vote);
+            }
+            WalRecord::Pop { id } => {
+                remove_entry(&mut state.entries, id);
+            }
+            WalRecord::Dead { letter } => {
+                remove_entry(&mut state.entries, letter.vote.id);
+                state.dead.insert(letter.vote.id, letter);
+            }
+            WalRecord::Requeue { vote } => {
+                state.dead.remove(&vote.id);
+                state.entries.push_back(vote);
+            }
+            WalRecord::Purge { id: Some(id) } => {
+                state.dead.remove(&id);
+            }
+            WalRecord::Purge { id: None } => {
+                state.dead.clear();
+            }
+            WalRecord::Expedite { at } => {
+                state.entries.iter_mut().for_each(|vote| vote.next_attempt_at = at);
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn remove_entry(entries: &mut VecDeque<CachedVote>, id: u64) {
+    if entries.front().is_some_and(|front| front.id == id) {
+        entries.pop_front();
+    } else {
+        entries.retain(|entry| entry.id != id);
+    }
+}
+
+/**
+Writes all live entries into a new segment, makes it durable and removes every older segment
+*/
+fn write_snapshot<'a>(dir: &Path, segment_id: u64, entries: impl Iterator<Item = &'a CachedVote>,
+                      dead: impl Iterator<Item = &'a DeadLetter>) -> io::Result<(File, u64)> {
+    let path = segment_path(dir, segment_id);
+    let tmp_path = path.with_extension("tmp");
+    let mut size = 0;
+    {
+        let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_path)?;
+        let records = entries.map(|vote| WalRecord::Push { vote: vote.clone() })
+            .chain(dead.map(|letter| WalRecord::Dead { letter: letter.clone() }));
+        for record in records {
+            let mut line = serde_json::to_vec(&record).unwrap();
+            line.push(b'\n');
+            file.write_all(&line)?;
+            size += line.len() as u64;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(dir)?;
+    for (old_id, old_path) in list_segments(dir)? {
+        if old_id < segment_id {
+            fs::remove_file(old_path)?;
+        }
+    }
+    sync_dir(dir)?;
+    let segment = OpenOptions::new().append(true).open(&path)?;
+    return Ok((segment, size));
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    return File::open(dir)?.sync_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+    A fresh directory below the system temp dir, removed again when dropped
+    */
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            return TestDir(std::env::temp_dir().join(format!("vote-wal-test-{:016x}", rand::random::<u64>())));
+        }
+
+        fn segments(&self) -> Vec<u64> {
+            return list_segments(&self.0).unwrap().into_iter().map(|(segment_id, _)| segment_id).collect();
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn vote(user: u64) -> VoteRequest {
+        let vote = format!(r#"{{"bot":"1","user":"{}","type":"upvote","isWeekend":false,"query":null,"src":"topgg"}}"#, user);
+        return serde_json::from_str(vote.as_str()).unwrap();
+    }
+
+    async fn queued_users(cache: &WalVoteCache) -> Vec<u64> {
+        let (_, votes) = cache.queued(&QueueFilter::default(), 0, 100).await;
+        return votes.iter().map(|vote| vote.vote.user.0).collect();
+    }
+
+    async fn dead_users(cache: &WalVoteCache) -> Vec<u64> {
+        return cache.dead_letters(0, 100).await.iter().map(|letter| letter.vote.vote.user.0).collect();
+    }
+
+    #[tokio::test]
+    async fn replays_log_after_restart() {
+        let dir = TestDir::new();
+        {
+            let mut cache = WalVoteCache::open(&dir.0, 1 << 20).unwrap();
+            for user in 1..=5 {
+                cache.cache_failed_vote(vote(user), "failed".to_owned(), 0).await;
+            }
+            let delivered = cache.poll().await.unwrap();
+            cache.ack(&delivered).await;
+            let rejected = cache.poll().await.unwrap();
+            cache.dead_letter(rejected, "rejected".to_owned()).await;
+            let mut retried = cache.poll().await.unwrap();
+            retried.record_failure("failed again".to_owned());
+            cache.return_failed_retry(retried).await;
+            cache.dead_letter_failed_vote(vote(6), "rejected".to_owned()).await;
+            cache.dead_letter_failed_vote(vote(7), "rejected".to_owned()).await;
+            assert!(cache.requeue_dead_letter(5).await);
+            assert_eq!(cache.purge_dead_letters(Some(6)).await, 1);
+            assert!(cache.remove_queued(3).await);
+            // still in flight when the cache is closed, so it is delivered again after the restart
+            assert_eq!(cache.poll().await.unwrap().id, 2);
+        }
+
+        let cache = WalVoteCache::open(&dir.0, 1 << 20).unwrap();
+        assert_eq!(queued_users(&cache).await, vec![3, 5, 6]);
+        assert_eq!(cache.queued_vote(2).await.unwrap().attempts, 2);
+        assert_eq!(dead_users(&cache).await, vec![2]);
+        assert_eq!(dir.segments().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn skips_torn_record() {
+        let dir = TestDir::new();
+        {
+            let mut cache = WalVoteCache::open(&dir.0, 1 << 20).unwrap();
+            cache.cache_failed_vote(vote(1), "failed".to_owned(), 0).await;
+            cache.segment.write_all(br#"{"op":"push","vote":{"id":1,"#).unwrap();
+        }
+
+        let cache = WalVoteCache::open(&dir.0, 1 << 20).unwrap();
+        assert_eq!(queued_users(&cache).await, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn compacts_log_into_live_entries() {
+        let dir = TestDir::new();
+        let mut cache = WalVoteCache::open(&dir.0, 1024).unwrap();
+        for user in 1..=20 {
+            cache.cache_failed_vote(vote(user), "failed".to_owned(), 0).await;
+            let delivered = cache.poll().await.unwrap();
+            cache.ack(&delivered).await;
+        }
+        cache.cache_failed_vote(vote(21), "failed".to_owned(), 0).await;
+        let in_flight = cache.poll().await.unwrap();
+        for user in 22..=30 {
+            cache.cache_failed_vote(vote(user), "failed".to_owned(), 0).await;
+        }
+
+        assert!(cache.segment_id > 1);
+        assert_eq!(dir.segments(), vec![cache.segment_id]);
+        assert!(cache.segment_size - cache.snapshot_size < 1024);
+        drop(cache);
+
+        let mut cache = WalVoteCache::open(&dir.0, 1024).unwrap();
+        assert_eq!(queued_users(&cache).await, (21..=30).collect::<Vec<_>>());
+        assert_eq!(cache.poll().await.unwrap().id, in_flight.id);
+        assert_eq!(cache.next_id, 30);
+    }
+
+    #[tokio::test]
+    async fn failed_write_is_not_ready_until_recovered() {
+        let dir = TestDir::new();
+        let mut cache = WalVoteCache::open(&dir.0, 1 << 20).unwrap();
+        cache.cache_failed_vote(vote(1), "failed".to_owned(), 0).await;
+        // a handle opened for reading only fails every write
+        cache.segment = File::open(segment_path(&dir.0, cache.segment_id)).unwrap();
+
+        cache.cache_failed_vote(vote(2), "failed".to_owned(), 0).await;
+        assert!(cache.check_ready().await.is_err());
+
+        cache.cache_failed_vote(vote(3), "failed".to_owned(), 0).await;
+        assert_eq!(cache.check_ready().await, Ok(()));
+        drop(cache);
+
+        let cache = WalVoteCache::open(&dir.0, 1 << 20).unwrap();
+        assert_eq!(queued_users(&cache).await, vec![1, 2, 3]);
+    }
+}